@@ -1,11 +1,14 @@
 use arbitrary::{Result, Unstructured};
-use dlmalloc::Dlmalloc;
+use dlmalloc::{Allocator, Dlmalloc};
 use std::cmp;
 
 const MAX_ALLOCATED: usize = 100 << 20; // 100 MB
 
 pub fn run(u: &mut Unstructured<'_>) -> Result<()> {
-    let mut a = Dlmalloc::new();
+    run_with(u, Dlmalloc::new())
+}
+
+pub fn run_with<A: Allocator>(u: &mut Unstructured<'_>, mut a: Dlmalloc<A>) -> Result<()> {
     let mut ptrs = Vec::new();
     let mut allocated = 0;
     unsafe {
@@ -27,6 +30,24 @@ pub fn run(u: &mut Unstructured<'_>) -> Result<()> {
                 continue;
             }
 
+            // Rarely throw away everything at once.
+            if u.ratio(1, 1000)? {
+                a.reset();
+                ptrs.clear();
+                allocated = 0;
+                continue;
+            }
+
+            // Occasionally hand free pages back to the system.
+            if u.ratio(1, 100)? {
+                if u.arbitrary()? {
+                    a.purge(u.arbitrary()?);
+                } else {
+                    a.set_decay_time(u.int_in_range(0..=3)?);
+                    a.decay(u.int_in_range(0..=100)?);
+                }
+            }
+
             // 1/100 chance of reallocating a pointer to a different size.
             if ptrs.len() > 0 && u.ratio(1, 100)? {
                 let idx = u.choose_index(ptrs.len())?;
@@ -0,0 +1,292 @@
+use crate::locked::{Guard, LockedDlmalloc};
+use crate::{Allocator, RawMutex, Stats};
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// A set of `N` independent heaps, each behind its own lock of type `L`,
+/// usable as a `#[global_allocator]`.
+///
+/// Where [`GlobalDlmalloc`](crate::GlobalDlmalloc) and [`LockedDlmalloc`]
+/// serialize every allocation on one lock, this type shards allocations
+/// across several arenas, much like glibc does:
+///
+/// * Allocations go to the arena which last served one without contention.
+///   If that arena is busy the others are tried in turn and the first free
+///   one becomes the new starting point, so threads spread out across arenas
+///   as they contend. Only if all of them are busy does the thread block,
+///   moving the starting point on to the next arena round-robin.
+/// * Each allocation is prefixed with a header of `2 * size_of::<usize>()`
+///   bytes, just in front of the returned pointer, recording the arena it
+///   came from, so frees and reallocations from any thread go back to the
+///   right arena. Allocations aligned to more than that are aligned by
+///   padding in front of the header.
+/// * A free first tries to take its arena's lock without blocking. If the
+///   arena is busy the block is pushed onto a lock-free list of remote frees
+///   for that arena instead, and whoever next locks the arena to allocate or
+///   free gives those blocks back to it. Blocks waiting on this list count as
+///   used memory in [`ArenaDlmalloc::stats`].
+///
+/// ```ignore
+/// #[global_allocator]
+/// static A: ArenaDlmalloc<System, SpinLock, 4> =
+///     ArenaDlmalloc::new([const { LockedDlmalloc::new(Dlmalloc::new()) }; 4]);
+/// ```
+pub struct ArenaDlmalloc<A, L, const N: usize> {
+    arenas: [LockedDlmalloc<A, L>; N],
+    remote: [AtomicPtr<u8>; N],
+    // The arena `pick` tries first.
+    next: AtomicUsize,
+}
+
+impl<A, L: RawMutex, const N: usize> ArenaDlmalloc<A, L, N> {
+    /// Creates a new set of arenas out of `arenas`, which must not have
+    /// allocated anything yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` is zero.
+    pub const fn new(arenas: [LockedDlmalloc<A, L>; N]) -> ArenaDlmalloc<A, L, N> {
+        assert!(N > 0);
+        ArenaDlmalloc {
+            arenas,
+            remote: [const { AtomicPtr::new(ptr::null_mut()) }; N],
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Runs `f` with exclusive access to each arena in turn, for example to
+    /// change their configuration at runtime.
+    ///
+    /// The same restrictions as [`LockedDlmalloc::configure`] apply.
+    pub fn configure(&self, mut f: impl FnMut(&mut crate::Dlmalloc<A>)) {
+        for arena in self.arenas.iter() {
+            arena.configure(&mut f);
+        }
+    }
+
+    /// Locks an arena for an allocation by the current thread, returning its
+    /// index along with the guard.
+    fn pick(&self) -> (usize, Guard<'_, A, L>) {
+        let start = self.next.load(Ordering::Relaxed) % N;
+        for i in 0..N {
+            let idx = (start + i) % N;
+            if let Some(guard) = self.arenas[idx].try_lock() {
+                if i != 0 {
+                    self.next.store(idx, Ordering::Relaxed);
+                }
+                return (idx, guard);
+            }
+        }
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % N;
+        (idx, self.arenas[idx].lock())
+    }
+
+    /// Pushes `base`, the start of an allocation from arena `idx`, onto that
+    /// arena's list of remote frees.
+    unsafe fn push_remote(&self, idx: usize, base: *mut u8) {
+        let head = &self.remote[idx];
+        let mut next = head.load(Ordering::Relaxed);
+        loop {
+            *base.cast::<*mut u8>() = next;
+            match head.compare_exchange_weak(next, base, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(cur) => next = cur,
+            }
+        }
+    }
+}
+
+impl<A: Allocator, L: RawMutex, const N: usize> ArenaDlmalloc<A, L, N> {
+    /// Frees everything on arena `idx`'s list of remote frees, whose lock is
+    /// held by `guard`.
+    ///
+    /// The whole list is taken at once, so pushes racing with this never see
+    /// a node being removed and there's no ABA problem.
+    unsafe fn drain(&self, idx: usize, guard: &mut Guard<'_, A, L>) {
+        let head = &self.remote[idx];
+        if head.load(Ordering::Relaxed).is_null() {
+            return;
+        }
+        let mut cur = head.swap(ptr::null_mut(), Ordering::Acquire);
+        while !cur.is_null() {
+            let next = *cur.cast::<*mut u8>();
+            guard.heap().c_free(cur);
+            cur = next;
+        }
+    }
+
+    /// Gives unused memory in every arena back to the system, see
+    /// [`Dlmalloc::trim`](crate::Dlmalloc::trim).
+    ///
+    /// Pending remote frees are returned to their arenas first.
+    pub fn trim(&self, pad: usize) -> bool {
+        let mut released = false;
+        for (idx, arena) in self.arenas.iter().enumerate() {
+            let mut guard = arena.lock();
+            unsafe {
+                self.drain(idx, &mut guard);
+                released |= guard.heap().trim(pad);
+            }
+        }
+        released
+    }
+
+    /// Purges free memory in every arena, up to `max_bytes` in total, see
+    /// [`Dlmalloc::purge`](crate::Dlmalloc::purge).
+    ///
+    /// Pending remote frees are returned to their arenas first.
+    pub fn purge(&self, max_bytes: usize) -> usize {
+        let mut purged = 0;
+        for (idx, arena) in self.arenas.iter().enumerate() {
+            if purged == max_bytes {
+                break;
+            }
+            let mut guard = arena.lock();
+            unsafe {
+                self.drain(idx, &mut guard);
+                purged += guard.heap().purge(max_bytes - purged);
+            }
+        }
+        purged
+    }
+
+    /// Purges memory which has been free for a while in every arena, see
+    /// [`Dlmalloc::decay`](crate::Dlmalloc::decay).
+    ///
+    /// Pending remote frees are returned to their arenas first.
+    pub fn decay(&self, now: u64) -> usize {
+        let mut purged = 0;
+        for (idx, arena) in self.arenas.iter().enumerate() {
+            let mut guard = arena.lock();
+            unsafe {
+                self.drain(idx, &mut guard);
+                purged += guard.heap().decay(now);
+            }
+        }
+        purged
+    }
+
+    /// Returns statistics summed across all arenas, see
+    /// [`Dlmalloc::stats`](crate::Dlmalloc::stats).
+    ///
+    /// Arenas are visited one at a time, so the result isn't a consistent
+    /// snapshot if other threads are allocating. `max_footprint` is the sum
+    /// of each arena's maximum, which may overstate the true peak.
+    pub fn stats(&self) -> Stats {
+        let mut total = Stats::default();
+        for arena in self.arenas.iter() {
+            let stats = arena.stats();
+            total.footprint += stats.footprint;
+            total.max_footprint += stats.max_footprint;
+            total.used += stats.used;
+            total.free += stats.free;
+            total.free_chunks += stats.free_chunks;
+            total.releasable += stats.releasable;
+        }
+        total
+    }
+}
+
+/// The size of the header in front of every allocation, which is also the
+/// alignment asked of the arenas.
+const HEADER: usize = 2 * mem::size_of::<usize>();
+
+/// Returns how many bytes to allocate in front of an allocation aligned to
+/// `align`: the header, and room to move the user pointer up to the next
+/// multiple of `align` past it.
+fn padding(align: usize) -> usize {
+    HEADER + align.saturating_sub(HEADER)
+}
+
+/// Returns where the start of the block holding the user pointer `ptr` is
+/// stored, the first word of its header.
+unsafe fn base_slot(ptr: *mut u8) -> *mut *mut u8 {
+    ptr.cast::<*mut u8>().sub(2)
+}
+
+/// Returns where the owning arena's index is stored for the user pointer
+/// `ptr`, the last word of its header.
+unsafe fn arena_slot(ptr: *mut u8) -> *mut usize {
+    ptr.cast::<usize>().sub(1)
+}
+
+unsafe impl<A: Allocator, L: RawMutex, const N: usize> GlobalAlloc for ArenaDlmalloc<A, L, N> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let size = match layout.size().checked_add(padding(layout.align())) {
+            Some(size) => size,
+            None => return ptr::null_mut(),
+        };
+        let (idx, mut guard) = self.pick();
+        self.drain(idx, &mut guard);
+        let base = guard.heap().malloc(size, HEADER);
+        drop(guard);
+        if base.is_null() {
+            return base;
+        }
+        let start = base.add(HEADER);
+        let ptr = start.add(start.align_offset(layout.align()));
+        *base_slot(ptr) = base;
+        *arena_slot(ptr) = idx;
+        ptr
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let idx = *arena_slot(ptr);
+        let base = *base_slot(ptr);
+        match self.arenas[idx].try_lock() {
+            Some(mut guard) => {
+                self.drain(idx, &mut guard);
+                let size = layout.size() + padding(layout.align());
+                guard.heap().free(base, size, HEADER)
+            }
+            None => self.push_remote(idx, base),
+        }
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            ptr::write_bytes(ptr, 0, layout.size());
+        }
+        ptr
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // A moved block may need a different amount of padding to keep the
+        // user pointer aligned, so over-aligned allocations are copied.
+        if layout.align() > HEADER {
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            let new = self.alloc(new_layout);
+            if !new.is_null() {
+                ptr::copy_nonoverlapping(ptr, new, cmp::min(layout.size(), new_size));
+                self.dealloc(ptr, layout);
+            }
+            return new;
+        }
+        let new_total = match new_size.checked_add(HEADER) {
+            Some(size) => size,
+            None => return ptr::null_mut(),
+        };
+        let idx = *arena_slot(ptr);
+        let mut guard = self.arenas[idx].lock();
+        self.drain(idx, &mut guard);
+        let base = guard
+            .heap()
+            .realloc(*base_slot(ptr), layout.size() + HEADER, HEADER, new_total);
+        if base.is_null() {
+            return base;
+        }
+        // The header is copied along with the data, and the new block comes
+        // from the same arena, so only where it starts needs updating.
+        let ptr = base.add(HEADER);
+        *base_slot(ptr) = base;
+        ptr
+    }
+}
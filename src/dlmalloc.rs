@@ -23,25 +23,50 @@ use core::cmp;
 use core::mem;
 use core::ptr;
 
-use crate::Allocator;
+use crate::{Allocator, SegmentInfo, Stats};
 
+// `repr(C)` keeps the layout the same from one build to the next, as a heap
+// image stores this struct, see `image.rs`.
+#[repr(C)]
 pub struct Dlmalloc<A> {
     smallmap: u32,
     treemap: u32,
-    smallbins: [*mut Chunk; (NSMALLBINS + 1) * 2],
-    treebins: [*mut TreeChunk; NTREEBINS],
+    smallbins: [Link<Chunk>; (NSMALLBINS + 1) * 2],
+    treebins: [Link<TreeChunk>; NTREEBINS],
     dvsize: usize,
     topsize: usize,
-    dv: *mut Chunk,
-    top: *mut Chunk,
+    dv: Link<Chunk>,
+    top: Link<Chunk>,
     footprint: usize,
     max_footprint: usize,
     seg: Segment,
     trim_check: usize,
-    least_addr: *mut u8,
+    least_addr: Link<u8>,
     release_checks: usize,
     granularity: usize,
     max_release_check_rate: usize,
+    // Allocation log for checkpoints, see `checkpoint`.
+    log: Link<Link<u8>>,
+    log_len: usize,
+    log_cap: usize,
+    checkpoints: usize,
+    // Caller-provided memory to use as the first segment, see `with_region`.
+    region: *mut u8,
+    region_len: usize,
+    // See `decay`.
+    decay_time: u64,
+    // Address space reserved from the system allocator which the top segment
+    // grows into, see `commit_reserved`. Everything from `reserve_top` up to
+    // `reserve_end` is still uncommitted.
+    reserve_base: *mut u8,
+    reserve_top: *mut u8,
+    reserve_end: *mut u8,
+    reserve_flags: u32,
+    reserve_size: usize,
+    // See `set_root`.
+    root: Link<u8>,
+    // `usize::MAX` in a relative heap, zero otherwise, see `set_relative`.
+    relative: usize,
     system_allocator: A,
 }
 unsafe impl<A: Send> Send for Dlmalloc<A> {}
@@ -63,32 +88,70 @@ const DEFAULT_TRIM_THRESHOLD: usize = 2 * 1024 * 1024;
 // runtime `malloc_alignment()` so chunk math stays sound.
 const MIN_GRANULARITY: usize = 2 * mem::size_of::<usize>();
 
+// The chunk layout, which `tcache.rs` relies on to sort requests by the
+// chunk size they get: chunks are aligned to `MALLOC_ALIGNMENT`, have
+// `CHUNK_OVERHEAD` bytes of header while in use and are never smaller than
+// `MIN_CHUNK_SIZE`.
+pub const MALLOC_ALIGNMENT: usize = 2 * mem::size_of::<usize>();
+pub const CHUNK_OVERHEAD: usize = mem::size_of::<usize>();
+pub const MIN_CHUNK_SIZE: usize = align_up(mem::size_of::<Chunk>(), MALLOC_ALIGNMENT);
+
 #[repr(C)]
 struct Chunk {
     prev_foot: usize,
     head: usize,
-    prev: *mut Chunk,
-    next: *mut Chunk,
+    prev: Link<Chunk>,
+    next: Link<Chunk>,
 }
 
 #[repr(C)]
 struct TreeChunk {
     chunk: Chunk,
-    child: [*mut TreeChunk; 2],
-    parent: *mut TreeChunk,
+    child: [Link<TreeChunk>; 2],
+    parent: Link<TreeChunk>,
     index: u32,
 }
 
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct Segment {
-    base: *mut u8,
+    base: Link<u8>,
     size: usize,
-    next: *mut Segment,
+    next: Link<Segment>,
     flags: u32,
 }
 
-fn align_up(a: usize, alignment: usize) -> usize {
+/// A pointer kept in the heap's bookkeeping, read and written with
+/// `Dlmalloc::ptr` and `Dlmalloc::link`.
+///
+/// It's normally just the address, but in a relative heap it's the offset of
+/// the address from the `Dlmalloc` itself, see `Dlmalloc::set_relative`.
+#[repr(transparent)]
+struct Link<T>(*mut T);
+
+impl<T> Link<T> {
+    const NULL: Link<T> = Link(ptr::null_mut());
+
+    fn is_null(self) -> bool {
+        self.0.is_null()
+    }
+}
+
+impl<T> Clone for Link<T> {
+    fn clone(&self) -> Link<T> {
+        *self
+    }
+}
+
+impl<T> Copy for Link<T> {}
+
+impl<T> PartialEq for Link<T> {
+    fn eq(&self, other: &Link<T>) -> bool {
+        self.0 == other.0
+    }
+}
+
+const fn align_up(a: usize, alignment: usize) -> usize {
     debug_assert!(alignment.is_power_of_two());
     (a + (alignment - 1)) & !(alignment - 1)
 }
@@ -115,33 +178,114 @@ impl<A> Dlmalloc<A> {
         Dlmalloc {
             smallmap: 0,
             treemap: 0,
-            smallbins: [ptr::null_mut(); (NSMALLBINS + 1) * 2],
-            treebins: [ptr::null_mut(); NTREEBINS],
+            smallbins: [Link::NULL; (NSMALLBINS + 1) * 2],
+            treebins: [Link::NULL; NTREEBINS],
             dvsize: 0,
             topsize: 0,
-            dv: ptr::null_mut(),
-            top: ptr::null_mut(),
+            dv: Link::NULL,
+            top: Link::NULL,
             footprint: 0,
             max_footprint: 0,
             seg: Segment {
-                base: ptr::null_mut(),
+                base: Link::NULL,
                 size: 0,
-                next: ptr::null_mut(),
+                next: Link::NULL,
                 flags: 0,
             },
             trim_check: 0,
-            least_addr: ptr::null_mut(),
+            least_addr: Link::NULL,
             release_checks: 0,
             granularity: 64 * 1024,
             max_release_check_rate: 4095,
+            log: Link::NULL,
+            log_len: 0,
+            log_cap: 0,
+            checkpoints: 0,
+            region: ptr::null_mut(),
+            region_len: 0,
+            decay_time: 0,
+            reserve_base: ptr::null_mut(),
+            reserve_top: ptr::null_mut(),
+            reserve_end: ptr::null_mut(),
+            reserve_flags: 0,
+            reserve_size: 0,
+            root: Link::NULL,
+            relative: 0,
             system_allocator,
         }
     }
 
+    /// Creates a heap which first carves allocations out of the `len` bytes
+    /// at `base`, only asking `system_allocator` for memory once those run
+    /// out.
+    ///
+    /// The region is installed as an `EXTERN` segment on the first
+    /// allocation, so it's never handed back to the allocator.
+    pub const fn with_region(system_allocator: A, base: *mut u8, len: usize) -> Dlmalloc<A> {
+        let mut heap = Dlmalloc::new(system_allocator);
+        heap.region = base;
+        heap.region_len = len;
+        heap
+    }
+
     pub fn allocator(&self) -> &A {
         &self.system_allocator
     }
 
+    /// Returns the pointer stored with `set_root`.
+    pub fn root(&self) -> *mut u8 {
+        self.ptr(self.root)
+    }
+
+    /// Stores a pointer with the heap's bookkeeping. In a relative heap it's
+    /// stored as an offset like the heap's own links, so it stays valid
+    /// wherever the heap is mapped.
+    pub fn set_root(&mut self, root: *mut u8) {
+        self.root = self.link(root);
+    }
+
+    /// Makes the heap store each link in its bookkeeping as an offset from
+    /// `self` rather than as an address.
+    ///
+    /// The bookkeeping of a relative heap then holds no addresses at all, so
+    /// if `self` lives in the same memory as the heap's segments, the heap
+    /// works at whatever address that memory is mapped, for example by
+    /// several processes sharing it at once. In exchange `self` must never
+    /// move while the heap has any segments, which rules out `destroy`, so
+    /// only heap images are relative, see `image.rs`.
+    ///
+    /// Returns `false`, changing nothing, once the heap has any segments.
+    pub fn set_relative(&mut self, relative: bool) -> bool {
+        if !self.top.is_null() || !self.root.is_null() {
+            return false;
+        }
+        self.relative = if relative { usize::MAX } else { 0 };
+        true
+    }
+
+    /// Returns what links in a relative heap are offsets from, or zero.
+    fn link_base(&self) -> usize {
+        self as *const Self as usize & self.relative
+    }
+
+    /// Returns the pointer `link` refers to.
+    fn ptr<T>(&self, link: Link<T>) -> *mut T {
+        if link.is_null() {
+            return link.0;
+        }
+        link.0.wrapping_byte_add(self.link_base())
+    }
+
+    /// Returns a link referring to `ptr`.
+    fn link<T>(&self, ptr: *mut T) -> Link<T> {
+        if ptr.is_null() {
+            return Link::NULL;
+        }
+        let link = Link(ptr.wrapping_byte_sub(self.link_base()));
+        debug_assert!(!link.is_null());
+        link
+    }
+
     pub fn allocator_mut(&mut self) -> &mut A {
         &mut self.system_allocator
     }
@@ -170,9 +314,9 @@ impl<A> Dlmalloc<A> {
     /// Unlike C dlmalloc's `mallopt(M_GRANULARITY, ...)`, which rejects
     /// sub-page values, this accepts any pow-of-two >= the malloc alignment.
     /// Sub-page granularity is intentionally allowed for embedded targets
-    /// that need tightly-packed allocations on small heaps; the underlying
-    /// system allocator may still round individual requests up to its page
-    /// size.
+    /// that need tightly-packed allocations on small heaps, but segments
+    /// still grow and shrink by at least a page of the system allocator, see
+    /// `sys_granularity`.
     ///
     /// For best results call this before the first allocation; existing
     /// segments retain their original alignment.
@@ -184,6 +328,18 @@ impl<A> Dlmalloc<A> {
         true
     }
 
+    /// Sets how much address space to reserve at once when the system
+    /// allocator supports reserving memory separately from committing it. The
+    /// default of `0` disables reservations.
+    pub const fn set_reserve_size(&mut self, size: usize) {
+        self.reserve_size = size;
+    }
+
+    /// Sets how long a chunk must stay free before `decay` purges it.
+    pub const fn set_decay_time(&mut self, time: u64) {
+        self.decay_time = time;
+    }
+
     /// Returns the value to seed `release_checks` with. When the configured
     /// rate is zero the periodic release pass is disabled by using
     /// `usize::MAX` so the countdown never reaches zero.
@@ -199,12 +355,12 @@ impl<A> Dlmalloc<A> {
 impl<A: Allocator> Dlmalloc<A> {
     // TODO: can we get rid of this?
     pub fn malloc_alignment(&self) -> usize {
-        mem::size_of::<usize>() * 2
+        MALLOC_ALIGNMENT
     }
 
     // TODO: dox
     fn chunk_overhead(&self) -> usize {
-        mem::size_of::<usize>()
+        CHUNK_OVERHEAD
     }
 
     fn mmap_chunk_overhead(&self) -> usize {
@@ -228,7 +384,7 @@ impl<A: Allocator> Dlmalloc<A> {
 
     // TODO: dox
     fn min_chunk_size(&self) -> usize {
-        align_up(mem::size_of::<Chunk>(), self.malloc_alignment())
+        MIN_CHUNK_SIZE
     }
 
     // TODO: dox
@@ -236,6 +392,13 @@ impl<A: Allocator> Dlmalloc<A> {
         self.min_chunk_size() - self.chunk_overhead() - 1
     }
 
+    /// Returns the unit segments grow and shrink by: the configured
+    /// granularity, but never less than a page of the system allocator, as
+    /// it can only map and unmap whole pages.
+    fn sys_granularity(&self) -> usize {
+        cmp::max(self.granularity, self.system_allocator.page_size())
+    }
+
     // TODO: dox
     fn max_request(&self) -> usize {
         // min_sys_alloc_space: the largest `X` such that
@@ -243,11 +406,11 @@ impl<A: Allocator> Dlmalloc<A> {
         //                                `max_request` will not be honored
         //   + self.top_foot_size()
         //   + self.malloc_alignment()
-        //   + self.granularity
+        //   + self.sys_granularity()
         // ==
         //   usize::MAX
         let min_sys_alloc_space =
-            ((!0 - (self.granularity + self.top_foot_size() + self.malloc_alignment()) + 1)
+            ((!0 - (self.sys_granularity() + self.top_foot_size() + self.malloc_alignment()) + 1)
                 & !self.malloc_alignment())
                 - self.chunk_overhead()
                 + 1;
@@ -337,7 +500,7 @@ impl<A: Allocator> Dlmalloc<A> {
                 idx += !smallbits & 1;
 
                 let b = self.smallbin_at(idx);
-                let p = (*b).prev;
+                let p = self.ptr((*b).prev);
                 self.unlink_first_small_chunk(b, p, idx);
                 let smallsize = self.small_index2size(idx);
                 Chunk::set_inuse_and_pinuse(p, smallsize);
@@ -354,7 +517,7 @@ impl<A: Allocator> Dlmalloc<A> {
                     let leastbit = least_bit(leftbits);
                     let i = leastbit.trailing_zeros();
                     let b = self.smallbin_at(i);
-                    let p = (*b).prev;
+                    let p = self.ptr((*b).prev);
                     debug_assert_eq!(Chunk::size(p), self.small_index2size(i));
                     self.unlink_first_small_chunk(b, p, i);
                     let smallsize = self.small_index2size(i);
@@ -398,17 +561,17 @@ impl<A: Allocator> Dlmalloc<A> {
         // exhausting the entire chunk
         if nb <= self.dvsize {
             let rsize = self.dvsize - nb;
-            let p = self.dv;
+            let p = self.ptr(self.dv);
             if rsize >= self.min_chunk_size() {
-                self.dv = Chunk::plus_offset(p, nb);
+                self.dv = self.link(Chunk::plus_offset(p, nb));
                 self.dvsize = rsize;
-                let r = self.dv;
+                let r = self.ptr(self.dv);
                 Chunk::set_size_and_pinuse_of_free_chunk(r, rsize);
                 Chunk::set_size_and_pinuse_of_inuse_chunk(p, nb);
             } else {
                 let dvs = self.dvsize;
                 self.dvsize = 0;
-                self.dv = ptr::null_mut();
+                self.dv = Link::NULL;
                 Chunk::set_inuse_and_pinuse(p, dvs);
             }
             let ret = Chunk::to_mem(p);
@@ -421,12 +584,12 @@ impl<A: Allocator> Dlmalloc<A> {
         if nb < self.topsize {
             self.topsize -= nb;
             let rsize = self.topsize;
-            let p = self.top;
-            self.top = Chunk::plus_offset(p, nb);
-            let r = self.top;
+            let p = self.ptr(self.top);
+            self.top = self.link(Chunk::plus_offset(p, nb));
+            let r = self.ptr(self.top);
             (*r).head = rsize | PINUSE;
             Chunk::set_size_and_pinuse_of_inuse_chunk(p, nb);
-            self.check_top_chunk(self.top);
+            self.check_top_chunk(self.ptr(self.top));
             let ret = Chunk::to_mem(p);
             self.check_malloced_chunk(ret, nb);
             self.check_malloc_state();
@@ -439,13 +602,23 @@ impl<A: Allocator> Dlmalloc<A> {
     /// allocates system resources
     unsafe fn sys_alloc(&mut self, size: usize) -> *mut u8 {
         self.check_malloc_state();
+        if self.top.is_null() {
+            self.install_region();
+            if !self.top.is_null() && size < self.topsize {
+                return self.alloc_from_top(size);
+            }
+        }
+
         // keep in sync with max_request
         let asize = align_up(
             size + self.top_foot_size() + self.malloc_alignment(),
-            self.granularity,
+            self.sys_granularity(),
         );
 
-        let (tbase, tsize, flags) = self.system_allocator.alloc(asize);
+        let (tbase, tsize, flags) = match self.commit_reserved(asize) {
+            Some(mem) => mem,
+            None => self.system_allocator.alloc(asize),
+        };
         if tbase.is_null() {
             return tbase;
         }
@@ -454,42 +627,34 @@ impl<A: Allocator> Dlmalloc<A> {
         self.max_footprint = cmp::max(self.max_footprint, self.footprint);
 
         if self.top.is_null() {
-            if self.least_addr.is_null() || tbase < self.least_addr {
-                self.least_addr = tbase;
-            }
-            self.seg.base = tbase;
-            self.seg.size = tsize;
-            self.seg.flags = flags;
-            self.release_checks = self.release_check_target();
-            self.init_bins();
-            let tsize = tsize - self.top_foot_size();
-            self.init_top(tbase.cast(), tsize);
+            self.init_first_segment(tbase, tsize, flags);
         // let mn = Chunk::next(Chunk::from_mem(self as *mut _ as *mut u8));
         // let top_foot_size = self.top_foot_size();
         // self.init_top(mn, tbase as usize + tsize - mn as usize - top_foot_size);
         } else {
             let mut sp: *mut Segment = &mut self.seg;
-            while !sp.is_null() && tbase != Segment::top(sp) {
-                sp = (*sp).next;
+            while !sp.is_null() && tbase != self.segment_top(sp) {
+                sp = self.ptr((*sp).next);
             }
             if !sp.is_null()
                 && !Segment::is_extern(sp)
-                && Segment::sys_flags(sp) == flags
-                && Segment::holds(sp, self.top.cast())
+                && Segment::sys_flags(sp) == flags >> 1
+                && self.segment_holds(sp, self.ptr(self.top).cast())
             {
                 (*sp).size += tsize;
-                let ptr = self.top;
+                let ptr = self.ptr(self.top);
                 let size = self.topsize + tsize;
                 self.init_top(ptr, size);
             } else {
-                self.least_addr = cmp::min(tbase, self.least_addr);
+                self.least_addr = self.link(cmp::min(tbase, self.ptr(self.least_addr)));
                 let mut sp: *mut Segment = &mut self.seg;
-                while !sp.is_null() && (*sp).base != tbase.wrapping_add(tsize) {
-                    sp = (*sp).next;
+                while !sp.is_null() && self.ptr((*sp).base) != tbase.wrapping_add(tsize) {
+                    sp = self.ptr((*sp).next);
                 }
-                if !sp.is_null() && !Segment::is_extern(sp) && Segment::sys_flags(sp) == flags {
-                    let oldbase = (*sp).base;
-                    (*sp).base = tbase;
+                if !sp.is_null() && !Segment::is_extern(sp) && Segment::sys_flags(sp) == flags >> 1
+                {
+                    let oldbase = self.ptr((*sp).base);
+                    (*sp).base = self.link(tbase);
                     (*sp).size += tsize;
                     return self.prepend_alloc(tbase, oldbase, size);
                 } else {
@@ -499,23 +664,166 @@ impl<A: Allocator> Dlmalloc<A> {
         }
 
         if size < self.topsize {
-            self.topsize -= size;
-            let rsize = self.topsize;
-            let p = self.top;
-            self.top = Chunk::plus_offset(p, size);
-            let r = self.top;
-            (*r).head = rsize | PINUSE;
-            Chunk::set_size_and_pinuse_of_inuse_chunk(p, size);
-            let ret = Chunk::to_mem(p);
-            self.check_top_chunk(self.top);
-            self.check_malloced_chunk(ret, size);
-            self.check_malloc_state();
-            return ret;
+            return self.alloc_from_top(size);
         }
 
         return ptr::null_mut();
     }
 
+    /// Commits the next `size` bytes of the current reservation, first
+    /// reserving a new range of address space if there's no room left in it.
+    ///
+    /// The committed memory immediately follows the top segment whenever
+    /// possible, so `sys_alloc` extends that segment in place. Returns `None`
+    /// if the system allocator can't reserve memory.
+    unsafe fn commit_reserved(&mut self, size: usize) -> Option<(*mut u8, usize, u32)> {
+        if self.reserve_size == 0 {
+            return None;
+        }
+        // Only keep growing the reservation while it ends the top segment,
+        // so it never ends up split across several segments.
+        let extends = !self.top.is_null() && self.segment_top(&self.seg) == self.reserve_top;
+        let room = self.reserve_end as usize - self.reserve_top as usize;
+        if !extends || room < size {
+            let rsize = align_up(cmp::max(self.reserve_size, size), self.sys_granularity());
+            let (base, rsize, flags) = self.system_allocator.reserve(rsize);
+            if base.is_null() {
+                return None;
+            }
+            if rsize < size {
+                self.sys(flags).free(base, rsize);
+                return None;
+            }
+            self.release_reserve_tail();
+            self.reserve_base = base;
+            self.reserve_top = base;
+            self.reserve_end = base.wrapping_add(rsize);
+            self.reserve_flags = flags;
+        }
+        if !self.sys(self.reserve_flags).commit(self.reserve_top, size) {
+            return None;
+        }
+        let ptr = self.reserve_top;
+        self.reserve_top = ptr.wrapping_add(size);
+        Some((ptr, size, self.reserve_flags))
+    }
+
+    /// Gives the uncommitted end of the current reservation back to the
+    /// system, after which what was committed of it is an ordinary segment.
+    unsafe fn release_reserve_tail(&mut self) {
+        if self.reserve_base.is_null() {
+            return;
+        }
+        let base = self.reserve_base;
+        let oldsize = self.reserve_end as usize - base as usize;
+        let newsize = self.reserve_top as usize - base as usize;
+        let sys = self.sys(self.reserve_flags);
+        if newsize == 0 {
+            sys.free(base, oldsize);
+        } else if newsize < oldsize {
+            sys.free_part(base, oldsize, newsize);
+        }
+        self.reserve_base = ptr::null_mut();
+        self.reserve_top = ptr::null_mut();
+        self.reserve_end = ptr::null_mut();
+    }
+
+    /// Returns the allocator which provided a segment with `flags`.
+    fn sys(&self, flags: u32) -> &dyn Allocator {
+        self.system_allocator.segment_allocator(flags)
+    }
+
+    /// Frees the segment of `size` bytes at `base` with `flags` back to the
+    /// system, along with the rest of the reservation it ends, if any.
+    unsafe fn free_segment(&mut self, base: *mut u8, size: usize, flags: u32) -> bool {
+        if self.reserve_top.is_null() || base.wrapping_add(size) != self.reserve_top {
+            return self.sys(flags).free(base, size);
+        }
+        let reserved = self.reserve_end as usize - base as usize;
+        if !self.sys(flags).free(base, reserved) {
+            return false;
+        }
+        self.reserve_base = ptr::null_mut();
+        self.reserve_top = ptr::null_mut();
+        self.reserve_end = ptr::null_mut();
+        true
+    }
+
+    /// Installs the region passed to `with_region`, if it's still pending.
+    unsafe fn install_region(&mut self) {
+        if self.region.is_null() {
+            return;
+        }
+        let (base, len) = (self.region, self.region_len);
+        self.region = ptr::null_mut();
+        self.region_len = 0;
+        self.add_region(base, len);
+    }
+
+    /// Adds the `len` bytes at `base` to the heap as an `EXTERN` segment,
+    /// returning `false` if they're too small to hold one.
+    pub unsafe fn add_region(&mut self, base: *mut u8, len: usize) -> bool {
+        // A region given to `with_region` comes first, as it would have been
+        // installed on the first allocation anyway.
+        if self.top.is_null() {
+            self.install_region();
+        }
+        // Chunk sizes must stay multiples of the alignment, so drop any
+        // unaligned tail.
+        let len = len.saturating_sub((base as usize + len) % self.malloc_alignment());
+        if len < self.min_segment_size() {
+            return false;
+        }
+        self.footprint += len;
+        self.max_footprint = cmp::max(self.max_footprint, self.footprint);
+        if self.top.is_null() {
+            self.init_first_segment(base, len, EXTERN);
+        } else {
+            self.least_addr = self.link(cmp::min(base, self.ptr(self.least_addr)));
+            self.add_segment(base, len, EXTERN);
+        }
+        self.check_malloc_state();
+        true
+    }
+
+    /// Splits a chunk of `size` bytes, which must be less than `topsize`, off
+    /// the start of top.
+    unsafe fn alloc_from_top(&mut self, size: usize) -> *mut u8 {
+        self.topsize -= size;
+        let rsize = self.topsize;
+        let p = self.ptr(self.top);
+        self.top = self.link(Chunk::plus_offset(p, size));
+        let r = self.ptr(self.top);
+        (*r).head = rsize | PINUSE;
+        Chunk::set_size_and_pinuse_of_inuse_chunk(p, size);
+        let ret = Chunk::to_mem(p);
+        self.check_top_chunk(self.ptr(self.top));
+        self.check_malloced_chunk(ret, size);
+        self.check_malloc_state();
+        ret
+    }
+
+    /// Sets up the `tsize` bytes at `tbase` as the first segment of an empty
+    /// heap, all of it becoming top.
+    unsafe fn init_first_segment(&mut self, tbase: *mut u8, tsize: usize, flags: u32) {
+        if self.least_addr.is_null() || tbase < self.ptr(self.least_addr) {
+            self.least_addr = self.link(tbase);
+        }
+        self.seg.base = self.link(tbase);
+        self.seg.size = tsize;
+        self.seg.flags = flags;
+        self.release_checks = self.release_check_target();
+        self.init_bins();
+        let tsize = tsize - self.top_foot_size();
+        self.init_top(tbase.cast(), tsize);
+    }
+
+    /// Returns the smallest region which can hold a segment with a top chunk
+    /// of at least the minimum chunk size, wherever it's aligned.
+    fn min_segment_size(&self) -> usize {
+        self.top_foot_size() + self.malloc_alignment() + self.min_chunk_size()
+    }
+
     pub unsafe fn realloc(&mut self, oldmem: *mut u8, bytes: usize) -> *mut u8 {
         if bytes >= self.max_request() {
             return ptr::null_mut();
@@ -551,7 +859,7 @@ impl<A: Allocator> Dlmalloc<A> {
                 self.dispose_chunk(r, rsize);
             }
             p
-        } else if next == self.top {
+        } else if next == self.ptr(self.top) {
             // extend into top
             if oldsize + self.topsize <= nb {
                 return ptr::null_mut();
@@ -561,10 +869,10 @@ impl<A: Allocator> Dlmalloc<A> {
             let newtop = Chunk::plus_offset(p, nb);
             Chunk::set_inuse(p, nb);
             (*newtop).head = newtopsize | PINUSE;
-            self.top = newtop;
+            self.top = self.link(newtop);
             self.topsize = newtopsize;
             p
-        } else if next == self.dv {
+        } else if next == self.ptr(self.dv) {
             // extend into dv
             let dvs = self.dvsize;
             if oldsize + dvs < nb {
@@ -578,13 +886,13 @@ impl<A: Allocator> Dlmalloc<A> {
                 Chunk::set_size_and_pinuse_of_free_chunk(r, dsize);
                 Chunk::clear_pinuse(n);
                 self.dvsize = dsize;
-                self.dv = r;
+                self.dv = self.link(r);
             } else {
                 // exhaust dv
                 let newsize = oldsize + dvs;
                 Chunk::set_inuse(p, newsize);
                 self.dvsize = 0;
-                self.dv = ptr::null_mut();
+                self.dv = Link::NULL;
             }
             return p;
         } else if !Chunk::cinuse(next) {
@@ -640,7 +948,7 @@ impl<A: Allocator> Dlmalloc<A> {
         (*newp).head = psize;
         (*Chunk::plus_offset(newp, psize)).head = Chunk::fencepost_head();
         (*Chunk::plus_offset(newp, psize + mem::size_of::<usize>())).head = 0;
-        self.least_addr = cmp::min(ptr, self.least_addr);
+        self.least_addr = self.link(cmp::min(ptr, self.ptr(self.least_addr)));
         self.footprint = self.footprint + newmmsize - oldmmsize;
         self.max_footprint = cmp::max(self.max_footprint, self.footprint);
         self.check_mmapped_chunk(newp);
@@ -735,7 +1043,7 @@ impl<A: Allocator> Dlmalloc<A> {
             let prev = Chunk::minus_offset(p, prevsize);
             psize += prevsize;
             p = prev;
-            if p != self.dv {
+            if p != self.ptr(self.dv) {
                 self.unlink_chunk(p, prevsize);
             } else if (*next).head & INUSE == INUSE {
                 self.dvsize = psize;
@@ -746,20 +1054,20 @@ impl<A: Allocator> Dlmalloc<A> {
 
         if !Chunk::cinuse(next) {
             // consolidate forward
-            if next == self.top {
+            if next == self.ptr(self.top) {
                 self.topsize += psize;
                 let tsize = self.topsize;
-                self.top = p;
+                self.top = self.link(p);
                 (*p).head = tsize | PINUSE;
-                if p == self.dv {
-                    self.dv = ptr::null_mut();
+                if p == self.ptr(self.dv) {
+                    self.dv = Link::NULL;
                     self.dvsize = 0;
                 }
                 return;
-            } else if next == self.dv {
+            } else if next == self.ptr(self.dv) {
                 self.dvsize += psize;
                 let dsize = self.dvsize;
-                self.dv = p;
+                self.dv = self.link(p);
                 Chunk::set_size_and_pinuse_of_free_chunk(p, dsize);
                 return;
             } else {
@@ -767,7 +1075,7 @@ impl<A: Allocator> Dlmalloc<A> {
                 psize += nsize;
                 self.unlink_chunk(next, nsize);
                 Chunk::set_size_and_pinuse_of_free_chunk(p, psize);
-                if p == self.dv {
+                if p == self.ptr(self.dv) {
                     self.dvsize = psize;
                     return;
                 }
@@ -783,7 +1091,7 @@ impl<A: Allocator> Dlmalloc<A> {
         let p = Chunk::plus_offset(ptr, offset);
         let size = size - offset;
 
-        self.top = p;
+        self.top = self.link(p);
         self.topsize = size;
         (*p).head = size | PINUSE;
         (*Chunk::plus_offset(p, size)).head = self.top_foot_size();
@@ -793,8 +1101,8 @@ impl<A: Allocator> Dlmalloc<A> {
     unsafe fn init_bins(&mut self) {
         for i in 0..NSMALLBINS_U32 {
             let bin = self.smallbin_at(i);
-            (*bin).next = bin;
-            (*bin).prev = bin;
+            (*bin).next = self.link(bin);
+            (*bin).prev = self.link(bin);
         }
     }
 
@@ -811,16 +1119,16 @@ impl<A: Allocator> Dlmalloc<A> {
         debug_assert!(qsize >= self.min_chunk_size());
 
         // consolidate the remainder with the first chunk of the old base
-        if oldfirst == self.top {
+        if oldfirst == self.ptr(self.top) {
             self.topsize += qsize;
             let tsize = self.topsize;
-            self.top = q;
+            self.top = self.link(q);
             (*q).head = tsize | PINUSE;
             self.check_top_chunk(q);
-        } else if oldfirst == self.dv {
+        } else if oldfirst == self.ptr(self.dv) {
             self.dvsize += qsize;
             let dsize = self.dvsize;
-            self.dv = q;
+            self.dv = self.link(q);
             Chunk::set_size_and_pinuse_of_free_chunk(q, dsize);
         } else {
             if !Chunk::inuse(oldfirst) {
@@ -845,9 +1153,9 @@ impl<A: Allocator> Dlmalloc<A> {
         // TODO: what in the world is this function doing
 
         // Determine locations and sizes of segment, fenceposts, and the old top
-        let old_top = self.top.cast::<u8>();
+        let old_top = self.ptr(self.top).cast::<u8>();
         let oldsp = self.segment_holding(old_top);
-        let old_end = Segment::top(oldsp);
+        let old_end = self.segment_top(oldsp);
         let ssize = self.pad_request(mem::size_of::<Segment>());
         let offset = ssize + mem::size_of::<usize>() * 4 + self.malloc_alignment() - 1;
         let rawsp = old_end.wrapping_sub(offset);
@@ -872,10 +1180,10 @@ impl<A: Allocator> Dlmalloc<A> {
         debug_assert!(self.is_aligned(ss as usize));
         Chunk::set_size_and_pinuse_of_inuse_chunk(sp, ssize);
         *ss = self.seg; // push our current record
-        self.seg.base = tbase;
+        self.seg.base = self.link(tbase);
         self.seg.size = tsize;
         self.seg.flags = flags;
-        self.seg.next = ss;
+        self.seg.next = self.link(ss);
 
         // insert trailing fences
         loop {
@@ -899,30 +1207,56 @@ impl<A: Allocator> Dlmalloc<A> {
             self.insert_chunk(q, psize);
         }
 
-        self.check_top_chunk(self.top);
+        self.check_top_chunk(self.ptr(self.top));
         self.check_malloc_state();
     }
 
     unsafe fn segment_holding(&self, ptr: *mut u8) -> *mut Segment {
         let mut sp = &self.seg as *const Segment as *mut Segment;
         while !sp.is_null() {
-            if (*sp).base <= ptr && ptr < Segment::top(sp) {
+            if self.segment_holds(sp, ptr) {
                 return sp;
             }
-            sp = (*sp).next;
+            sp = self.ptr((*sp).next);
         }
         ptr::null_mut()
     }
 
+    unsafe fn segment_holds(&self, seg: *const Segment, addr: *mut u8) -> bool {
+        self.ptr((*seg).base) <= addr && addr < self.segment_top(seg)
+    }
+
+    unsafe fn segment_top(&self, seg: *const Segment) -> *mut u8 {
+        self.ptr((*seg).base).wrapping_add((*seg).size)
+    }
+
+    unsafe fn leftmost_child(&self, t: *mut TreeChunk) -> *mut TreeChunk {
+        let left = self.ptr((*t).child[0]);
+        if left.is_null() {
+            self.ptr((*t).child[1])
+        } else {
+            left
+        }
+    }
+
+    unsafe fn tree_next(&self, t: *mut TreeChunk) -> *mut TreeChunk {
+        self.ptr((*TreeChunk::chunk(t)).next).cast()
+    }
+
+    unsafe fn tree_prev(&self, t: *mut TreeChunk) -> *mut TreeChunk {
+        self.ptr((*TreeChunk::chunk(t)).prev).cast()
+    }
+
     unsafe fn tmalloc_small(&mut self, size: usize) -> *mut u8 {
         let leastbit = least_bit(self.treemap);
         let i = leastbit.trailing_zeros();
-        let mut v = *self.treebin_at(i);
+        let root = *self.treebin_at(i);
+        let mut v = self.ptr(root);
         let mut t = v;
         let mut rsize = Chunk::size(TreeChunk::chunk(t)) - size;
 
         loop {
-            t = TreeChunk::leftmost_child(t);
+            t = self.leftmost_child(t);
             if t.is_null() {
                 break;
             }
@@ -952,7 +1286,8 @@ impl<A: Allocator> Dlmalloc<A> {
         let mut v = ptr::null_mut();
         let mut rsize = !size + 1;
         let idx = self.compute_tree_index(size);
-        let mut t = *self.treebin_at(idx);
+        let root = *self.treebin_at(idx);
+        let mut t = self.ptr(root);
         if !t.is_null() {
             // Traverse thre tree for this bin looking for a node with size
             // equal to the `size` above.
@@ -968,8 +1303,8 @@ impl<A: Allocator> Dlmalloc<A> {
                         break;
                     }
                 }
-                let rt = (*t).child[1];
-                t = (*t).child[(sizebits >> (mem::size_of::<usize>() * 8 - 1)) & 1];
+                let rt = self.ptr((*t).child[1]);
+                t = self.ptr((*t).child[(sizebits >> (mem::size_of::<usize>() * 8 - 1)) & 1]);
                 if !rt.is_null() && rt != t {
                     rst = rt;
                 }
@@ -989,7 +1324,8 @@ impl<A: Allocator> Dlmalloc<A> {
             if leftbits != 0 {
                 let leastbit = least_bit(leftbits);
                 let i = leastbit.trailing_zeros();
-                t = *self.treebin_at(i);
+                let root = *self.treebin_at(i);
+                t = self.ptr(root);
             }
         }
 
@@ -1000,7 +1336,7 @@ impl<A: Allocator> Dlmalloc<A> {
                 rsize = csize - size;
                 v = t;
             }
-            t = TreeChunk::leftmost_child(t);
+            t = self.leftmost_child(t);
         }
 
         // If dv is a better fit, then return null so malloc will use it
@@ -1028,7 +1364,7 @@ impl<A: Allocator> Dlmalloc<A> {
         self.smallbins.as_mut_ptr().wrapping_add(idx).cast()
     }
 
-    unsafe fn treebin_at(&mut self, idx: u32) -> *mut *mut TreeChunk {
+    unsafe fn treebin_at(&mut self, idx: u32) -> *mut Link<TreeChunk> {
         let idx = usize::try_from(idx).unwrap();
         debug_assert!(idx < self.treebins.len());
         self.treebins.as_mut_ptr().wrapping_add(idx)
@@ -1047,15 +1383,15 @@ impl<A: Allocator> Dlmalloc<A> {
     }
 
     unsafe fn unlink_first_small_chunk(&mut self, head: *mut Chunk, next: *mut Chunk, idx: u32) {
-        let ptr = (*next).prev;
+        let ptr = self.ptr((*next).prev);
         debug_assert!(next != head);
         debug_assert!(next != ptr);
         debug_assert_eq!(Chunk::size(next), self.small_index2size(idx));
         if head == ptr {
             self.clear_smallmap(idx);
         } else {
-            (*ptr).next = head;
-            (*head).prev = ptr;
+            (*ptr).next = self.link(head);
+            (*head).prev = self.link(ptr);
         }
     }
 
@@ -1063,11 +1399,11 @@ impl<A: Allocator> Dlmalloc<A> {
         let dvs = self.dvsize;
         debug_assert!(self.is_small(dvs));
         if dvs != 0 {
-            let dv = self.dv;
+            let dv = self.ptr(self.dv);
             self.insert_small_chunk(dv, dvs);
         }
         self.dvsize = size;
-        self.dv = chunk;
+        self.dv = self.link(chunk);
     }
 
     unsafe fn insert_chunk(&mut self, chunk: *mut Chunk, size: usize) {
@@ -1087,52 +1423,52 @@ impl<A: Allocator> Dlmalloc<A> {
             (head, head)
         } else {
             let head = self.smallbin_at(idx);
-            ((*head).prev, head)
+            (self.ptr((*head).prev), head)
         };
 
-        (*head).prev = chunk;
-        (*f).next = chunk;
-        (*chunk).prev = f;
-        (*chunk).next = head;
+        (*head).prev = self.link(chunk);
+        (*f).next = self.link(chunk);
+        (*chunk).prev = self.link(f);
+        (*chunk).next = self.link(head);
     }
 
     unsafe fn insert_large_chunk(&mut self, chunk: *mut TreeChunk, size: usize) {
         let idx = self.compute_tree_index(size);
         let h = self.treebin_at(idx);
         (*chunk).index = idx;
-        (*chunk).child[0] = ptr::null_mut();
-        (*chunk).child[1] = ptr::null_mut();
+        (*chunk).child[0] = Link::NULL;
+        (*chunk).child[1] = Link::NULL;
         let chunkc = TreeChunk::chunk(chunk);
         if !self.treemap_is_marked(idx) {
-            *h = chunk;
-            (*chunk).parent = h.cast(); // TODO: dubious?
-            (*chunkc).next = chunkc;
-            (*chunkc).prev = chunkc;
+            *h = self.link(chunk);
+            (*chunk).parent = self.link(h.cast()); // TODO: dubious?
+            (*chunkc).next = self.link(chunkc);
+            (*chunkc).prev = self.link(chunkc);
             self.mark_treemap(idx);
         } else {
-            let mut t = *h;
+            let mut t = self.ptr(*h);
             let mut k = size << leftshift_for_tree_index(idx);
             loop {
                 if Chunk::size(TreeChunk::chunk(t)) != size {
                     let c = &mut (*t).child[(k >> mem::size_of::<usize>() * 8 - 1) & 1];
                     k <<= 1;
                     if !c.is_null() {
-                        t = *c;
+                        t = self.ptr(*c);
                     } else {
-                        *c = chunk;
-                        (*chunk).parent = t;
-                        (*chunkc).next = chunkc;
-                        (*chunkc).prev = chunkc;
+                        *c = self.link(chunk);
+                        (*chunk).parent = self.link(t);
+                        (*chunkc).next = self.link(chunkc);
+                        (*chunkc).prev = self.link(chunkc);
                         break;
                     }
                 } else {
                     let tc = TreeChunk::chunk(t);
-                    let f = (*tc).prev;
-                    (*f).next = chunkc;
-                    (*tc).prev = chunkc;
-                    (*chunkc).prev = f;
-                    (*chunkc).next = tc;
-                    (*chunk).parent = ptr::null_mut();
+                    let f = self.ptr((*tc).prev);
+                    (*f).next = self.link(chunkc);
+                    (*tc).prev = self.link(chunkc);
+                    (*chunkc).prev = self.link(f);
+                    (*chunkc).next = self.link(tc);
+                    (*chunk).parent = Link::NULL;
                     break;
                 }
             }
@@ -1172,8 +1508,8 @@ impl<A: Allocator> Dlmalloc<A> {
     }
 
     unsafe fn unlink_small_chunk(&mut self, chunk: *mut Chunk, size: usize) {
-        let f = (*chunk).prev;
-        let b = (*chunk).next;
+        let f = self.ptr((*chunk).prev);
+        let b = self.ptr((*chunk).next);
         let idx = self.small_index(size);
         debug_assert!(chunk != b);
         debug_assert!(chunk != f);
@@ -1181,38 +1517,38 @@ impl<A: Allocator> Dlmalloc<A> {
         if b == f {
             self.clear_smallmap(idx);
         } else {
-            (*f).next = b;
-            (*b).prev = f;
+            (*f).next = self.link(b);
+            (*b).prev = self.link(f);
         }
     }
 
     unsafe fn unlink_large_chunk(&mut self, chunk: *mut TreeChunk) {
-        let xp = (*chunk).parent;
+        let xp = self.ptr((*chunk).parent);
         let mut r;
-        if TreeChunk::next(chunk) != chunk {
-            let f = TreeChunk::prev(chunk);
-            r = TreeChunk::next(chunk);
-            (*f).chunk.next = TreeChunk::chunk(r);
-            (*r).chunk.prev = TreeChunk::chunk(f);
+        if self.tree_next(chunk) != chunk {
+            let f = self.tree_prev(chunk);
+            r = self.tree_next(chunk);
+            (*f).chunk.next = self.link(TreeChunk::chunk(r));
+            (*r).chunk.prev = self.link(TreeChunk::chunk(f));
         } else {
             let mut rp = &mut (*chunk).child[1];
             if rp.is_null() {
                 rp = &mut (*chunk).child[0];
             }
-            r = *rp;
+            r = self.ptr(*rp);
             if !rp.is_null() {
                 loop {
-                    let mut cp = &mut (**rp).child[1];
+                    let mut cp = &mut (*self.ptr(*rp)).child[1];
                     if cp.is_null() {
-                        cp = &mut (**rp).child[0];
+                        cp = &mut (*self.ptr(*rp)).child[0];
                     }
                     if cp.is_null() {
                         break;
                     }
                     rp = cp;
                 }
-                r = *rp;
-                *rp = ptr::null_mut();
+                r = self.ptr(*rp);
+                *rp = Link::NULL;
             }
         }
 
@@ -1221,30 +1557,30 @@ impl<A: Allocator> Dlmalloc<A> {
         }
 
         let h = self.treebin_at((*chunk).index);
-        if chunk == *h {
-            *h = r;
+        if chunk == self.ptr(*h) {
+            *h = self.link(r);
             if r.is_null() {
                 self.clear_treemap((*chunk).index);
             }
         } else {
-            if (*xp).child[0] == chunk {
-                (*xp).child[0] = r;
+            if self.ptr((*xp).child[0]) == chunk {
+                (*xp).child[0] = self.link(r);
             } else {
-                (*xp).child[1] = r;
+                (*xp).child[1] = self.link(r);
             }
         }
 
         if !r.is_null() {
-            (*r).parent = xp;
-            let c0 = (*chunk).child[0];
+            (*r).parent = self.link(xp);
+            let c0 = self.ptr((*chunk).child[0]);
             if !c0.is_null() {
-                (*r).child[0] = c0;
-                (*c0).parent = r;
+                (*r).child[0] = self.link(c0);
+                (*c0).parent = self.link(r);
             }
-            let c1 = (*chunk).child[1];
+            let c1 = self.ptr((*chunk).child[1]);
             if !c1.is_null() {
-                (*r).child[1] = c1;
-                (*c1).parent = r;
+                (*r).child[1] = self.link(c1);
+                (*c1).parent = self.link(r);
             }
         }
     }
@@ -1287,7 +1623,7 @@ impl<A: Allocator> Dlmalloc<A> {
             let prev = Chunk::minus_offset(p, prevsize);
             psize += prevsize;
             p = prev;
-            if p != self.dv {
+            if p != self.ptr(self.dv) {
                 self.unlink_chunk(p, prevsize);
             } else if (*next).head & INUSE == INUSE {
                 self.dvsize = psize;
@@ -1298,23 +1634,23 @@ impl<A: Allocator> Dlmalloc<A> {
 
         // Consolidate forward if we can
         if !Chunk::cinuse(next) {
-            if next == self.top {
+            if next == self.ptr(self.top) {
                 self.topsize += psize;
                 let tsize = self.topsize;
-                self.top = p;
+                self.top = self.link(p);
                 (*p).head = tsize | PINUSE;
-                if p == self.dv {
-                    self.dv = ptr::null_mut();
+                if p == self.ptr(self.dv) {
+                    self.dv = Link::NULL;
                     self.dvsize = 0;
                 }
                 if self.should_trim(tsize) {
                     self.sys_trim(0);
                 }
                 return;
-            } else if next == self.dv {
+            } else if next == self.ptr(self.dv) {
                 self.dvsize += psize;
                 let dsize = self.dvsize;
-                self.dv = p;
+                self.dv = self.link(p);
                 Chunk::set_size_and_pinuse_of_free_chunk(p, dsize);
                 return;
             } else {
@@ -1322,7 +1658,7 @@ impl<A: Allocator> Dlmalloc<A> {
                 psize += nsize;
                 self.unlink_chunk(next, nsize);
                 Chunk::set_size_and_pinuse_of_free_chunk(p, psize);
-                if p == self.dv {
+                if p == self.ptr(self.dv) {
                     self.dvsize = psize;
                     return;
                 }
@@ -1353,19 +1689,28 @@ impl<A: Allocator> Dlmalloc<A> {
         if pad < self.max_request() && !self.top.is_null() {
             pad += self.top_foot_size();
             if self.topsize > pad {
-                let unit = self.granularity;
+                let unit = self.sys_granularity();
                 let extra = ((self.topsize - pad + unit - 1) / unit - 1) * unit;
-                let sp = self.segment_holding(self.top.cast());
+                let sp = self.segment_holding(self.ptr(self.top).cast());
                 debug_assert!(!sp.is_null());
 
                 if !Segment::is_extern(sp) {
                     if Segment::can_release_part(&self.system_allocator, sp) {
                         if (*sp).size >= extra && !self.has_segment_link(sp) {
                             let newsize = (*sp).size - extra;
-                            if self
-                                .system_allocator
-                                .free_part((*sp).base, (*sp).size, newsize)
-                            {
+                            if self.segment_top(sp) == self.reserve_top {
+                                // Keep the address space so the segment can
+                                // grow back into it.
+                                let newtop = self.ptr((*sp).base).wrapping_add(newsize);
+                                if self.sys(self.reserve_flags).decommit(newtop, extra) {
+                                    self.reserve_top = newtop;
+                                    released = extra;
+                                }
+                            } else if self.sys((*sp).flags).free_part(
+                                self.ptr((*sp).base),
+                                (*sp).size,
+                                newsize,
+                            ) {
                                 released = extra;
                             }
                         }
@@ -1375,10 +1720,10 @@ impl<A: Allocator> Dlmalloc<A> {
                 if released != 0 {
                     (*sp).size -= released;
                     self.footprint -= released;
-                    let top = self.top;
+                    let top = self.ptr(self.top);
                     let topsize = self.topsize - released;
                     self.init_top(top, topsize);
-                    self.check_top_chunk(self.top);
+                    self.check_top_chunk(self.ptr(self.top));
                 }
             }
 
@@ -1395,10 +1740,10 @@ impl<A: Allocator> Dlmalloc<A> {
     unsafe fn has_segment_link(&self, ptr: *mut Segment) -> bool {
         let mut sp = &self.seg as *const Segment as *mut Segment;
         while !sp.is_null() {
-            if Segment::holds(ptr, sp.cast()) {
+            if self.segment_holds(ptr, sp.cast()) {
                 return true;
             }
-            sp = (*sp).next;
+            sp = self.ptr((*sp).next);
         }
         false
     }
@@ -1408,11 +1753,11 @@ impl<A: Allocator> Dlmalloc<A> {
         let mut released = 0;
         let mut nsegs = 0;
         let mut pred: *mut Segment = &mut self.seg;
-        let mut sp = (*pred).next;
+        let mut sp = self.ptr((*pred).next);
         while !sp.is_null() {
-            let base = (*sp).base;
+            let base = self.ptr((*sp).base);
             let size = (*sp).size;
-            let next = (*sp).next;
+            let next = self.ptr((*sp).next);
             nsegs += 1;
 
             if Segment::can_release_part(&self.system_allocator, sp) && !Segment::is_extern(sp) {
@@ -1424,19 +1769,19 @@ impl<A: Allocator> Dlmalloc<A> {
                 let top = base.wrapping_add(size - self.top_foot_size());
                 if !Chunk::inuse(p) && chunk_top >= top {
                     let tp = p.cast::<TreeChunk>();
-                    debug_assert!(Segment::holds(sp, sp.cast()));
-                    if p == self.dv {
-                        self.dv = ptr::null_mut();
+                    debug_assert!(self.segment_holds(sp, sp.cast()));
+                    if p == self.ptr(self.dv) {
+                        self.dv = Link::NULL;
                         self.dvsize = 0;
                     } else {
                         self.unlink_large_chunk(tp);
                     }
-                    if self.system_allocator.free(base, size) {
+                    if self.free_segment(base, size, (*sp).flags) {
                         released += size;
                         self.footprint -= size;
                         // unlink our obsolete record
                         sp = pred;
-                        (*sp).next = next;
+                        (*sp).next = self.link(next);
                     } else {
                         // back out if we can't unmap
                         self.insert_large_chunk(tp, psize);
@@ -1459,7 +1804,7 @@ impl<A: Allocator> Dlmalloc<A> {
         debug_assert!(
             self.is_aligned(Chunk::to_mem(p) as usize) || (*p).head == Chunk::fencepost_head()
         );
-        debug_assert!(p as *mut u8 >= self.least_addr);
+        debug_assert!(p as *mut u8 >= self.ptr(self.least_addr));
     }
 
     unsafe fn check_top_chunk(&self, p: *mut Chunk) {
@@ -1467,17 +1812,17 @@ impl<A: Allocator> Dlmalloc<A> {
             return;
         }
         let sp = self.segment_holding(p.cast());
-        let sz = (*p).head & !INUSE;
+        let sz = Chunk::size(p);
         debug_assert!(!sp.is_null());
         debug_assert!(
             self.is_aligned(Chunk::to_mem(p) as usize) || (*p).head == Chunk::fencepost_head()
         );
-        debug_assert!(p as *mut u8 >= self.least_addr);
+        debug_assert!(p as *mut u8 >= self.ptr(self.least_addr));
         debug_assert_eq!(sz, self.topsize);
         debug_assert!(sz > 0);
         debug_assert_eq!(
             sz,
-            (*sp).base as usize + (*sp).size - p as usize - self.top_foot_size()
+            self.ptr((*sp).base) as usize + (*sp).size - p as usize - self.top_foot_size()
         );
         debug_assert!(Chunk::pinuse(p));
         debug_assert!(!Chunk::pinuse(Chunk::plus_offset(p, sz)));
@@ -1491,7 +1836,7 @@ impl<A: Allocator> Dlmalloc<A> {
             return;
         }
         let p = Chunk::from_mem(mem);
-        let sz = (*p).head & !INUSE;
+        let sz = Chunk::size(p);
         self.check_inuse_chunk(p);
         debug_assert_eq!(align_up(sz, self.malloc_alignment()), sz);
         debug_assert!(sz >= self.min_chunk_size());
@@ -1519,7 +1864,7 @@ impl<A: Allocator> Dlmalloc<A> {
         debug_assert!(
             self.is_aligned(Chunk::to_mem(p) as usize) || (*p).head == Chunk::fencepost_head()
         );
-        debug_assert!(p as *mut u8 >= self.least_addr);
+        debug_assert!(p as *mut u8 >= self.ptr(self.least_addr));
         debug_assert!(!self.is_small(sz));
         debug_assert_eq!(align_up(len, self.system_allocator.page_size()), len);
         debug_assert_eq!((*Chunk::plus_offset(p, sz)).head, Chunk::fencepost_head());
@@ -1539,15 +1884,15 @@ impl<A: Allocator> Dlmalloc<A> {
         debug_assert!(!Chunk::inuse(p));
         debug_assert!(!Chunk::pinuse(Chunk::next(p)));
         debug_assert!(!Chunk::mmapped(p));
-        if p != self.dv && p != self.top {
+        if p != self.ptr(self.dv) && p != self.ptr(self.top) {
             if sz >= self.min_chunk_size() {
                 debug_assert_eq!(align_up(sz, self.malloc_alignment()), sz);
                 debug_assert!(self.is_aligned(Chunk::to_mem(p) as usize));
                 debug_assert_eq!((*next).prev_foot, sz);
                 debug_assert!(Chunk::pinuse(p));
-                debug_assert!(next == self.top || Chunk::inuse(next));
-                debug_assert_eq!((*(*p).next).prev, p);
-                debug_assert_eq!((*(*p).prev).next, p);
+                debug_assert!(next == self.ptr(self.top) || Chunk::inuse(next));
+                debug_assert_eq!(self.ptr((*self.ptr((*p).next)).prev), p);
+                debug_assert_eq!(self.ptr((*self.ptr((*p).prev)).next), p);
             } else {
                 debug_assert_eq!(sz, mem::size_of::<usize>());
             }
@@ -1565,16 +1910,16 @@ impl<A: Allocator> Dlmalloc<A> {
             self.check_treebin(i);
         }
         if self.dvsize != 0 {
-            self.check_any_chunk(self.dv);
-            debug_assert_eq!(self.dvsize, Chunk::size(self.dv));
+            self.check_any_chunk(self.ptr(self.dv));
+            debug_assert_eq!(self.dvsize, Chunk::size(self.ptr(self.dv)));
             debug_assert!(self.dvsize >= self.min_chunk_size());
-            let dv = self.dv;
+            let dv = self.ptr(self.dv);
             debug_assert!(!self.bin_find(dv));
         }
         if !self.top.is_null() {
-            self.check_top_chunk(self.top);
+            self.check_top_chunk(self.ptr(self.top));
             debug_assert!(self.topsize > 0);
-            let top = self.top;
+            let top = self.ptr(self.top);
             debug_assert!(!self.bin_find(top));
         }
         let total = self.traverse_and_check();
@@ -1587,7 +1932,7 @@ impl<A: Allocator> Dlmalloc<A> {
             return;
         }
         let b = self.smallbin_at(idx);
-        let mut p = (*b).next;
+        let mut p = self.ptr((*b).next);
         let empty = self.smallmap & (1 << idx) == 0;
         if p == b {
             debug_assert!(empty)
@@ -1597,12 +1942,14 @@ impl<A: Allocator> Dlmalloc<A> {
                 let size = Chunk::size(p);
                 self.check_free_chunk(p);
                 debug_assert_eq!(self.small_index(size), idx);
-                debug_assert!((*p).next == b || Chunk::size((*p).next) == Chunk::size(p));
+                debug_assert!(
+                    self.ptr((*p).next) == b || Chunk::size(self.ptr((*p).next)) == Chunk::size(p)
+                );
                 let q = Chunk::next(p);
                 if (*q).head != Chunk::fencepost_head() {
                     self.check_inuse_chunk(q);
                 }
-                p = (*p).next;
+                p = self.ptr((*p).next);
             }
         }
     }
@@ -1611,7 +1958,8 @@ impl<A: Allocator> Dlmalloc<A> {
         if !cfg!(all(feature = "debug", debug_assertions)) {
             return;
         }
-        let t = *self.treebin_at(idx);
+        let root = *self.treebin_at(idx);
+        let t = self.ptr(root);
         let empty = self.treemap & (1 << idx) == 0;
         if t.is_null() {
             debug_assert!(empty);
@@ -1643,32 +1991,32 @@ impl<A: Allocator> Dlmalloc<A> {
             debug_assert_eq!(Chunk::size(uc), tsize);
             debug_assert!(!Chunk::inuse(uc));
             debug_assert!(!Chunk::pinuse(Chunk::next(uc)));
-            debug_assert_eq!((*(*uc).next).prev, uc);
-            debug_assert_eq!((*(*uc).prev).next, uc);
-            let left = (*u).child[0];
-            let right = (*u).child[1];
+            debug_assert_eq!(self.ptr((*self.ptr((*uc).next)).prev), uc);
+            debug_assert_eq!(self.ptr((*self.ptr((*uc).prev)).next), uc);
+            let left = self.ptr((*u).child[0]);
+            let right = self.ptr((*u).child[1]);
             if (*u).parent.is_null() {
                 debug_assert!(left.is_null());
                 debug_assert!(right.is_null());
             } else {
                 debug_assert!(head.is_null());
                 head = u;
-                debug_assert!((*u).parent != u);
+                debug_assert!(self.ptr((*u).parent) != u);
                 // TODO: unsure why this triggers UB in stacked borrows in MIRI
                 // (works in tree borrows though)
                 #[cfg(not(miri))]
                 debug_assert!(
-                    (*(*u).parent).child[0] == u
-                        || (*(*u).parent).child[1] == u
-                        || *((*u).parent as *mut *mut TreeChunk) == u
+                    self.ptr((*self.ptr((*u).parent)).child[0]) == u
+                        || self.ptr((*self.ptr((*u).parent)).child[1]) == u
+                        || self.ptr(*self.ptr((*u).parent).cast::<Link<TreeChunk>>()) == u
                 );
                 if !left.is_null() {
-                    debug_assert_eq!((*left).parent, u);
+                    debug_assert_eq!(self.ptr((*left).parent), u);
                     debug_assert!(left != u);
                     self.check_tree(left);
                 }
                 if !right.is_null() {
-                    debug_assert_eq!((*right).parent, u);
+                    debug_assert_eq!(self.ptr((*right).parent), u);
                     debug_assert!(right != u);
                     self.check_tree(right);
                 }
@@ -1679,7 +2027,7 @@ impl<A: Allocator> Dlmalloc<A> {
                 }
             }
 
-            u = TreeChunk::prev(u);
+            u = self.tree_prev(u);
             if u == t {
                 break;
             }
@@ -1705,7 +2053,7 @@ impl<A: Allocator> Dlmalloc<A> {
                 if p == chunk {
                     return true;
                 }
-                p = (*p).prev;
+                p = self.ptr((*p).prev);
                 if p == b {
                     return false;
                 }
@@ -1715,10 +2063,11 @@ impl<A: Allocator> Dlmalloc<A> {
             if !self.treemap_is_marked(tidx) {
                 return false;
             }
-            let mut t = *self.treebin_at(tidx);
+            let root = *self.treebin_at(tidx);
+            let mut t = self.ptr(root);
             let mut sizebits = size << leftshift_for_tree_index(tidx);
             while !t.is_null() && Chunk::size(TreeChunk::chunk(t)) != size {
-                t = (*t).child[(sizebits >> (mem::size_of::<usize>() * 8 - 1)) & 1];
+                t = self.ptr((*t).child[(sizebits >> (mem::size_of::<usize>() * 8 - 1)) & 1]);
                 sizebits <<= 1;
             }
             if t.is_null() {
@@ -1730,7 +2079,7 @@ impl<A: Allocator> Dlmalloc<A> {
                 if u == chunk {
                     return true;
                 }
-                u = TreeChunk::prev(u);
+                u = self.tree_prev(u);
                 if u == t {
                     return false;
                 }
@@ -1746,23 +2095,719 @@ impl<A: Allocator> Dlmalloc<A> {
         self.sys_trim(pad)
     }
 
+    /// Purges the interior of free chunks, including top, that haven't been
+    /// purged since they were last freed, stopping once `max_bytes` have
+    /// been purged. Returns the number of bytes purged.
+    ///
+    /// Purged chunks are marked with `FLAG4` in their head, see `purge_chunk`
+    /// for partly purged ones. Every path that resizes, merges or allocates
+    /// a free chunk rewrites its head, which drops the mark, so a chunk is
+    /// purged again once it may hold dirty pages.
+    ///
+    /// Caller-provided segments are left alone as there's no telling what
+    /// kind of memory they are.
+    pub unsafe fn purge(&mut self, max_bytes: usize) -> usize {
+        self.purge_free(max_bytes, None)
+    }
+
+    /// Purges the free chunks which have stayed free for at least
+    /// `decay_time` as of `now`.
+    ///
+    /// Rather than costing every `free` a timestamp, each free chunk big
+    /// enough to purge is stamped with `now` the first time a call to this
+    /// sees it, see `DecayStamp`, so its age is measured from then.
+    pub unsafe fn decay(&mut self, now: u64) -> usize {
+        self.purge_free(usize::MAX, Some(now))
+    }
+
+    unsafe fn purge_free(&mut self, max_bytes: usize, now: Option<u64>) -> usize {
+        let mut purged = 0;
+        let page = self.system_allocator.page_size();
+        if self.top.is_null() || max_bytes < page {
+            return purged;
+        }
+        let mut sp: *mut Segment = &mut self.seg;
+        while !sp.is_null() {
+            if !Segment::is_extern(sp) {
+                let mut q = self.align_as_chunk(self.ptr((*sp).base));
+                while self.segment_holds(sp, q.cast()) && (*q).head != Chunk::fencepost_head() {
+                    if !Chunk::inuse(q) && (*q).head & FLAG4 == 0 {
+                        let idle = match now {
+                            Some(now) => self.idle(q, now),
+                            None => true,
+                        };
+                        if idle {
+                            purged += self.purge_chunk(q, (*sp).flags, max_bytes - purged);
+                            // Nothing more fits once less than a page is left.
+                            if max_bytes - purged < page {
+                                return purged;
+                            }
+                        }
+                    }
+                    if q == self.ptr(self.top) {
+                        break;
+                    }
+                    q = Chunk::next(q);
+                }
+            }
+            sp = self.ptr((*sp).next);
+        }
+        purged
+    }
+
+    /// Returns whether the free chunk `p` has been free for at least
+    /// `decay_time` as of `now`, stamping it if it hasn't been seen before.
+    unsafe fn idle(&mut self, p: *mut Chunk, now: u64) -> bool {
+        if Chunk::size(p) < PURGE_OFFSET {
+            return false;
+        }
+        let stamp = p
+            .cast::<u8>()
+            .add(mem::size_of::<TreeChunk>())
+            .cast::<DecayStamp>();
+        let time = if (*stamp).is_valid(p) {
+            (*stamp).time
+        } else {
+            *stamp = DecayStamp::new(p, now);
+            now
+        };
+        now.saturating_sub(time) >= self.decay_time
+    }
+
+    /// Purges up to `max_bytes` of the whole pages of the free chunk `p`,
+    /// leaving its header, free list links, decay stamp and purge mark at the
+    /// start alone. The footer at its end lives in the next chunk, so it's
+    /// never touched.
+    ///
+    /// Pages are purged from the end of the chunk down. A chunk which is only
+    /// partly purged records where the purged pages start in its
+    /// `PurgeMark`, so the next call carries on below them, and a chunk which
+    /// has been purged completely is marked with `FLAG4` instead.
+    unsafe fn purge_chunk(&mut self, p: *mut Chunk, flags: u32, max_bytes: usize) -> usize {
+        let page = self.system_allocator.page_size();
+        let size = Chunk::size(p);
+        let start = align_up(p as usize + PURGE_OFFSET, page);
+        let mut end = (p as usize + size) & !(page - 1);
+        if end <= start {
+            return 0;
+        }
+        let mark = p
+            .cast::<u8>()
+            .add(mem::size_of::<TreeChunk>() + mem::size_of::<DecayStamp>())
+            .cast::<PurgeMark>();
+        if let Some(purged) = (*mark).purged(p, size) {
+            end = cmp::min(end, p as usize + purged);
+        }
+        if end <= start {
+            (*p).head |= FLAG4;
+            return 0;
+        }
+        let len = cmp::min(end - start, max_bytes & !(page - 1));
+        if len == 0 || !self.sys(flags).purge((end - len) as *mut u8, len) {
+            return 0;
+        }
+        if len == end - start {
+            (*p).head |= FLAG4;
+        } else {
+            *mark = PurgeMark::new(p, size, end - len - p as usize);
+        }
+        len
+    }
+
+    /// Frees every chunk at once while keeping all segments mapped.
+    ///
+    /// The head segment, `self.seg`, always holds `top` so it simply becomes
+    /// one big top chunk again. Every other segment has its record and
+    /// fenceposts at its end, see `add_segment`, and everything before the
+    /// record becomes one free chunk.
+    pub unsafe fn reset(&mut self) {
+        if self.top.is_null() {
+            return;
+        }
+        debug_assert!(self.segment_holds(&self.seg, self.ptr(self.top).cast()));
+        self.smallmap = 0;
+        self.treemap = 0;
+        self.treebins = [Link::NULL; NTREEBINS];
+        self.init_bins();
+        self.dv = Link::NULL;
+        self.dvsize = 0;
+
+        let mut sp = self.ptr(self.seg.next);
+        while !sp.is_null() {
+            let p = self.align_as_chunk(self.ptr((*sp).base));
+            let record = Chunk::from_mem(sp.cast());
+            if p < record {
+                let psize = record as usize - p as usize;
+                Chunk::set_free_with_pinuse(p, psize, record);
+                self.insert_chunk(p, psize);
+            } else {
+                (*record).head |= PINUSE;
+            }
+            sp = self.ptr((*sp).next);
+        }
+
+        let size = self.seg.size - self.top_foot_size();
+        self.init_top(self.ptr(self.seg.base).cast(), size);
+        // The log itself lived in the heap and is gone now too.
+        self.log = Link::NULL;
+        self.log_len = 0;
+        self.log_cap = 0;
+        self.checkpoints = 0;
+        self.check_malloc_state();
+    }
+
+    /// Checks, without writing anything, that the heap's bookkeeping is
+    /// consistent and lies entirely within `lo..hi`.
+    ///
+    /// Unlike `check_malloc_state` this is meant for memory that can't be
+    /// trusted, so it never follows a link before checking it's in range
+    /// and every walk is bounded. It covers what's needed to use the heap
+    /// safely: the configuration is one the setters allow, nothing is
+    /// reserved, every segment is caller-provided and in range, chunk sizes
+    /// and in-use bits agree with each other, free chunks are coalesced,
+    /// `top` and `dv` are where they claim to be, and the bins hold exactly
+    /// the other free chunks, each in the bin its size calls for.
+    pub unsafe fn validate(&mut self, lo: usize, hi: usize) -> bool {
+        let word = mem::size_of::<usize>();
+        let align = self.malloc_alignment();
+        if self.top.is_null() || lo > hi || (self.relative != 0 && self.relative != usize::MAX) {
+            return false;
+        }
+        let fits = |p: *mut u8, size: usize| {
+            let addr = p as usize;
+            lo <= addr && addr <= hi && size <= hi - addr
+        };
+
+        if !self.log.is_null()
+            && (self.log_len > self.log_cap
+                || !fits(self.ptr(self.log).cast(), self.log_cap.saturating_mul(word)))
+        {
+            return false;
+        }
+        if self.log.is_null() && self.log_len != 0 {
+            return false;
+        }
+
+        // The configuration must be one the setters allow, and nothing may be
+        // left to take from outside `lo..hi`: no pending region outside it and
+        // no reservation at all.
+        let least = self.ptr(self.least_addr);
+        if !self.granularity.is_power_of_two()
+            || self.granularity < cmp::max(MIN_GRANULARITY, self.system_allocator.page_size())
+            || self.reserve_size > usize::MAX - self.sys_granularity()
+            || self.release_checks == 0
+            || (self.max_release_check_rate == 0 && self.release_checks != usize::MAX)
+            || (self.region.is_null() && self.region_len != 0)
+            || (!self.region.is_null() && !fits(self.region, self.region_len))
+            || !self.reserve_base.is_null()
+            || !self.reserve_top.is_null()
+            || !self.reserve_end.is_null()
+            || least.is_null()
+            || !fits(least, 0)
+        {
+            return false;
+        }
+
+        // Walk every chunk, counting the free ones which should be in a bin.
+        let top = self.ptr(self.top);
+        let dv = self.ptr(self.dv);
+        let mut free = 0;
+        let mut found_dv = self.dvsize == 0;
+        let mut seg: *mut Segment = &mut self.seg;
+        let mut segments = 0;
+        loop {
+            let head = segments == 0;
+            segments += 1;
+            if segments > (hi - lo) / self.top_foot_size() + 1 || !Segment::is_extern(seg) {
+                return false;
+            }
+            let base = self.ptr((*seg).base);
+            let end = (base as usize).wrapping_add((*seg).size);
+            if !fits(base, (*seg).size) || base < least {
+                return false;
+            }
+            let mut q = self.align_as_chunk(base);
+            let mut prev_inuse = true;
+            loop {
+                // A damaged segment may not even reach its first chunk, so
+                // check `q` is inside it before measuring what's left.
+                if (q as usize) < base as usize || q as usize > end || end - (q as usize) < 2 * word
+                {
+                    return false;
+                }
+                if (*q).head == Chunk::fencepost_head() {
+                    // Only the end of a segment without `top` is fenced off.
+                    if head {
+                        return false;
+                    }
+                    break;
+                }
+                let size = Chunk::size(q);
+                let inuse = Chunk::cinuse(q);
+                if Chunk::pinuse(q) != prev_inuse
+                    || size & (align - 1) != 0
+                    || size == 0
+                    || size > end - q as usize
+                {
+                    return false;
+                }
+                if q == top {
+                    if !head || inuse || !prev_inuse || size != self.topsize {
+                        return false;
+                    }
+                    break;
+                }
+                if !inuse {
+                    if !prev_inuse
+                        || size < self.min_chunk_size()
+                        || size > end - q as usize - 2 * word
+                        || (*Chunk::plus_offset(q, size)).prev_foot != size
+                    {
+                        return false;
+                    }
+                    if self.dvsize != 0 && q == dv {
+                        if size != self.dvsize {
+                            return false;
+                        }
+                        found_dv = true;
+                    } else {
+                        free += 1;
+                    }
+                }
+                prev_inuse = inuse;
+                q = Chunk::plus_offset(q, size);
+            }
+
+            let next = self.ptr((*seg).next);
+            if next.is_null() {
+                break;
+            }
+            if !fits(next.cast(), mem::size_of::<Segment>()) {
+                return false;
+            }
+            seg = next;
+        }
+        if !found_dv {
+            return false;
+        }
+
+        // Every free chunk found above must be in its bin, and nothing else.
+        let mut budget = free;
+        for i in 0..NSMALLBINS_U32 {
+            let b = self.smallbin_at(i);
+            // An emptied bin keeps stale links, only its bit is cleared.
+            if !self.smallmap_is_marked(i) {
+                continue;
+            }
+            let mut prev = b;
+            let mut p = self.ptr((*b).next);
+            while p != b {
+                if budget == 0 || !fits(p.cast(), mem::size_of::<Chunk>()) {
+                    return false;
+                }
+                budget -= 1;
+                if Chunk::inuse(p)
+                    || !self.is_small(Chunk::size(p))
+                    || self.small_index(Chunk::size(p)) != i
+                    || self.ptr((*p).prev) != prev
+                {
+                    return false;
+                }
+                prev = p;
+                p = self.ptr((*p).next);
+            }
+            if self.ptr((*b).prev) != prev || prev == b {
+                return false;
+            }
+        }
+        for i in 0..NTREEBINS_U32 {
+            let h = self.treebin_at(i);
+            let t = self.ptr(*h);
+            if t.is_null() == self.treemap_is_marked(i) {
+                return false;
+            }
+            if !t.is_null() && !self.validate_tree(t, h.cast(), i, 0, &fits, &mut budget) {
+                return false;
+            }
+        }
+        budget == 0
+    }
+
+    /// Checks the tree of free chunks rooted at `t` for `validate`, taking
+    /// one from `budget` for each chunk.
+    unsafe fn validate_tree(
+        &self,
+        t: *mut TreeChunk,
+        parent: *mut TreeChunk,
+        idx: u32,
+        depth: usize,
+        fits: &dyn Fn(*mut u8, usize) -> bool,
+        budget: &mut usize,
+    ) -> bool {
+        if depth >= mem::size_of::<usize>() * 8 || !fits(t.cast(), mem::size_of::<TreeChunk>()) {
+            return false;
+        }
+        let size = Chunk::size(TreeChunk::chunk(t));
+        if self.ptr((*t).parent) != parent {
+            return false;
+        }
+
+        // Every chunk of the same size hangs off the tree node in a ring.
+        let mut u = t;
+        loop {
+            if *budget == 0 || !fits(u.cast(), mem::size_of::<TreeChunk>()) {
+                return false;
+            }
+            *budget -= 1;
+            let chunk = TreeChunk::chunk(u);
+            if Chunk::inuse(chunk)
+                || Chunk::size(chunk) != size
+                || self.is_small(size)
+                || self.compute_tree_index(size) != idx
+                || (*u).index != idx
+                || (u != t && !(*u).parent.is_null())
+            {
+                return false;
+            }
+            let next = self.ptr((*chunk).next).cast::<TreeChunk>();
+            if !fits(next.cast(), mem::size_of::<TreeChunk>())
+                || self.ptr((*TreeChunk::chunk(next)).prev) != u.cast()
+            {
+                return false;
+            }
+            u = next;
+            if u == t {
+                break;
+            }
+        }
+
+        for child in (*t).child {
+            let child = self.ptr(child);
+            if !child.is_null() && !self.validate_tree(child, t, idx, depth + 1, fits, budget) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Checkpoints
+    //
+    // While at least one checkpoint is active every chunk handed out through
+    // the public API is recorded in `log`, an array allocated from this heap,
+    // and marked with `FLAG4` in its head. Freeing a marked chunk replaces its
+    // log entry with null, so rolling back to a checkpoint only has to free
+    // the non-null entries recorded since then. To find that entry, chunks
+    // are allocated one word larger while a checkpoint is active, see
+    // `log_padding`, and the last word of a marked chunk holds its index in
+    // the log. `FLAG4` is otherwise unused in the heads of in-use chunks, and
+    // is dropped whenever a chunk is freed. In the heads of free chunks it
+    // means something else entirely, see `purge`.
+
+    /// Starts a checkpoint, returning the log length and checkpoint depth to
+    /// pass back to `rollback` or `commit`.
+    pub fn checkpoint(&mut self) -> (usize, usize) {
+        self.checkpoints += 1;
+        (self.log_len, self.checkpoints)
+    }
+
+    /// Makes sure there's room in the log to track one more chunk, returning
+    /// `false` if the log couldn't be grown.
+    pub unsafe fn reserve_log(&mut self) -> bool {
+        if self.checkpoints == 0 || self.log_len < self.log_cap {
+            return true;
+        }
+        let cap = cmp::max(64, self.log_cap * 2);
+        let bytes = cap * mem::size_of::<*mut u8>();
+        let log = if self.log.is_null() {
+            self.malloc(bytes)
+        } else {
+            self.realloc(self.ptr(self.log).cast(), bytes)
+        };
+        if log.is_null() {
+            return false;
+        }
+        self.log = self.link(log.cast());
+        self.log_cap = cap;
+        true
+    }
+
+    /// Returns how many bytes to add to the size of an allocation so that
+    /// it can be passed to `track`.
+    pub fn log_padding(&self) -> usize {
+        if self.checkpoints == 0 {
+            0
+        } else {
+            mem::size_of::<usize>()
+        }
+    }
+
+    /// Returns the last word of the in-use chunk `mem`, which holds its index
+    /// in the log if it's tracked.
+    unsafe fn log_slot(&self, mem: *mut u8) -> *mut usize {
+        let p = Chunk::from_mem(mem);
+        let usable = Chunk::size(p) - self.overhead_for(p);
+        mem.add(usable - mem::size_of::<usize>()).cast()
+    }
+
+    /// Records `mem`, a chunk just handed out, if a checkpoint is active.
+    ///
+    /// `reserve_log` must have returned `true` before `mem` was allocated,
+    /// with `log_padding` added to its size.
+    pub unsafe fn track(&mut self, mem: *mut u8) {
+        if self.checkpoints == 0 || mem.is_null() {
+            return;
+        }
+        debug_assert!(self.log_len < self.log_cap);
+        *self.ptr(self.log).add(self.log_len) = self.link(mem);
+        *self.log_slot(mem) = self.log_len;
+        self.log_len += 1;
+        (*Chunk::from_mem(mem)).head |= FLAG4;
+    }
+
+    /// Returns whether `mem`, an in-use chunk, is recorded in the log.
+    pub unsafe fn is_tracked(&self, mem: *mut u8) -> bool {
+        (*Chunk::from_mem(mem)).head & FLAG4 != 0
+    }
+
+    /// Removes `mem`, an in-use chunk about to be freed, from the log.
+    pub unsafe fn untrack(&mut self, mem: *mut u8) {
+        if self.is_tracked(mem) {
+            (*Chunk::from_mem(mem)).head &= !FLAG4;
+            self.forget(self.log_index(mem));
+        }
+    }
+
+    /// Returns the index in the log of `mem`, a tracked chunk.
+    pub unsafe fn log_index(&self, mem: *mut u8) -> usize {
+        let index = *self.log_slot(mem);
+        debug_assert!(index < self.log_len && self.ptr(*self.ptr(self.log).add(index)) == mem);
+        index
+    }
+
+    /// Removes the log entry at `index`, as returned by `log_index`, without
+    /// touching its chunk, which may already have been freed.
+    pub unsafe fn forget(&mut self, index: usize) {
+        *self.ptr(self.log).add(index) = Link::NULL;
+    }
+
+    /// Frees every chunk recorded since the checkpoint returned as `(len,
+    /// depth)`, ending it and any checkpoints started after it.
+    pub unsafe fn rollback(&mut self, len: usize, depth: usize) {
+        assert!(
+            depth <= self.checkpoints && len <= self.log_len,
+            "checkpoint already ended"
+        );
+        for i in (len..self.log_len).rev() {
+            let mem = self.ptr(*self.ptr(self.log).add(i));
+            if !mem.is_null() {
+                (*Chunk::from_mem(mem)).head &= !FLAG4;
+                self.free(mem);
+            }
+        }
+        self.log_len = len;
+        self.checkpoints = depth - 1;
+        if self.checkpoints == 0 {
+            self.release_log();
+        }
+    }
+
+    /// Ends the checkpoint returned as `(len, depth)`, and any started after
+    /// it, keeping its chunks.
+    ///
+    /// The chunks stay in the log if an outer checkpoint is still active, so
+    /// rolling back to that one frees them too.
+    pub unsafe fn commit(&mut self, len: usize, depth: usize) {
+        assert!(
+            depth <= self.checkpoints && len <= self.log_len,
+            "checkpoint already ended"
+        );
+        self.checkpoints = depth - 1;
+        if self.checkpoints == 0 {
+            for i in 0..self.log_len {
+                let mem = self.ptr(*self.ptr(self.log).add(i));
+                if !mem.is_null() {
+                    (*Chunk::from_mem(mem)).head &= !FLAG4;
+                }
+            }
+            self.release_log();
+        }
+    }
+
+    unsafe fn release_log(&mut self) {
+        if !self.log.is_null() {
+            self.free(self.ptr(self.log).cast());
+        }
+        self.log = Link::NULL;
+        self.log_len = 0;
+        self.log_cap = 0;
+    }
+
+    // port of `internal_mallinfo`
+    pub unsafe fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            footprint: self.footprint,
+            max_footprint: self.max_footprint,
+            ..Stats::default()
+        };
+        if self.top.is_null() {
+            return stats;
+        }
+        let mut nfree = 1; // top is always free
+        let mut mfree = self.topsize + self.top_foot_size();
+        let mut sp = &self.seg as *const Segment as *mut Segment;
+        while !sp.is_null() {
+            let mut q = self.align_as_chunk(self.ptr((*sp).base));
+            while self.segment_holds(sp, q.cast())
+                && q != self.ptr(self.top)
+                && (*q).head != Chunk::fencepost_head()
+            {
+                if !Chunk::inuse(q) {
+                    mfree += Chunk::size(q);
+                    nfree += 1;
+                }
+                q = Chunk::next(q);
+            }
+            sp = self.ptr((*sp).next);
+        }
+        stats.used = self.footprint - mfree;
+        stats.free = mfree;
+        stats.free_chunks = nfree;
+        stats.releasable = self.topsize;
+        stats
+    }
+
+    pub fn segments(&self) -> Segments<'_, A> {
+        let sp = if self.top.is_null() {
+            ptr::null_mut()
+        } else {
+            &self.seg as *const Segment as *mut Segment
+        };
+        Segments { heap: self, sp }
+    }
+
     pub unsafe fn destroy(mut self) -> usize {
         let mut freed = 0;
         let mut sp: *mut Segment = &mut self.seg;
         while !sp.is_null() {
-            let base = (*sp).base;
+            let base = self.ptr((*sp).base);
             let size = (*sp).size;
+            let flags = (*sp).flags;
             let can_free = !base.is_null() && !Segment::is_extern(sp);
-            sp = (*sp).next;
+            sp = self.ptr((*sp).next);
 
-            if can_free && self.system_allocator.free(base, size) {
+            if can_free && self.free_segment(base, size, flags) {
                 freed += size;
             }
         }
+        self.release_reserve_tail();
         freed
     }
 }
 
+/// Iterator over the segments of a heap, starting with the one holding top.
+pub struct Segments<'a, A> {
+    heap: &'a Dlmalloc<A>,
+    sp: *mut Segment,
+}
+
+impl<A: Allocator> Iterator for Segments<'_, A> {
+    type Item = SegmentInfo;
+
+    fn next(&mut self) -> Option<SegmentInfo> {
+        if self.sp.is_null() {
+            return None;
+        }
+        unsafe {
+            let sp = self.sp;
+            self.sp = self.heap.ptr((*sp).next);
+            let is_extern = Segment::is_extern(sp);
+            Some(SegmentInfo {
+                base: self.heap.ptr((*sp).base),
+                size: (*sp).size,
+                flags: (*sp).flags,
+                is_extern,
+                releasable: !is_extern
+                    && Segment::can_release_part(&self.heap.system_allocator, sp),
+            })
+        }
+    }
+}
+
+/// Records when `Dlmalloc::decay` first saw a free chunk, stored just after
+/// its tree chunk fields.
+///
+/// Nothing clears a stamp when its chunk is allocated, so `check` ties it to
+/// the chunk's address to tell it apart from leftover data. A stamp can
+/// still outlive its chunk being allocated and freed again, in which case
+/// the chunk is considered idle for longer than it really was, which only
+/// means it's purged a little early.
+#[repr(C)]
+struct DecayStamp {
+    time: u64,
+    check: u64,
+}
+
+// Arbitrary, just unlikely to turn up in leftover data.
+const DECAY_STAMP_MAGIC: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// Offset within a free chunk of the first byte which may be purged.
+const PURGE_OFFSET: usize =
+    mem::size_of::<TreeChunk>() + mem::size_of::<DecayStamp>() + mem::size_of::<PurgeMark>();
+
+impl DecayStamp {
+    fn new(p: *mut Chunk, time: u64) -> DecayStamp {
+        DecayStamp {
+            time,
+            check: time ^ p as u64 ^ DECAY_STAMP_MAGIC,
+        }
+    }
+
+    fn is_valid(&self, p: *mut Chunk) -> bool {
+        self.check == self.time ^ p as u64 ^ DECAY_STAMP_MAGIC
+    }
+}
+
+/// Records how far down `Dlmalloc::purge` has got in a free chunk which it
+/// has only partly purged, stored just after its decay stamp.
+///
+/// Like a `DecayStamp` this isn't cleared when the chunk is allocated, so
+/// `check` ties it to both the chunk's address and its size. A leftover
+/// mark for a chunk freed again at the same address with the same size
+/// leaves some of its pages unpurged until the chunk is next merged or
+/// split.
+#[repr(C)]
+struct PurgeMark {
+    // Offset within the chunk of the first purged byte.
+    purged: usize,
+    check: u64,
+}
+
+// Arbitrary, just unlikely to turn up in leftover data.
+const PURGE_MARK_MAGIC: u64 = 0xc2b2_ae3d_27d4_eb4f;
+
+impl PurgeMark {
+    fn new(p: *mut Chunk, size: usize, purged: usize) -> PurgeMark {
+        PurgeMark {
+            purged,
+            check: PurgeMark::check(p, size, purged),
+        }
+    }
+
+    fn check(p: *mut Chunk, size: usize, purged: usize) -> u64 {
+        p as u64 ^ (size as u64).rotate_left(32) ^ purged as u64 ^ PURGE_MARK_MAGIC
+    }
+
+    /// Returns the offset of the first purged byte of the chunk of `size`
+    /// bytes at `p`, if this mark belongs to it.
+    fn purged(&self, p: *mut Chunk, size: usize) -> Option<usize> {
+        if self.check == PurgeMark::check(p, size, self.purged) && self.purged <= size {
+            Some(self.purged)
+        } else {
+            None
+        }
+    }
+}
+
 const PINUSE: usize = 1 << 0;
 const CINUSE: usize = 1 << 1;
 const FLAG4: usize = 1 << 2;
@@ -1859,26 +2904,9 @@ impl Chunk {
 }
 
 impl TreeChunk {
-    unsafe fn leftmost_child(me: *mut TreeChunk) -> *mut TreeChunk {
-        let left = (*me).child[0];
-        if left.is_null() {
-            (*me).child[1]
-        } else {
-            left
-        }
-    }
-
     unsafe fn chunk(me: *mut TreeChunk) -> *mut Chunk {
         ptr::addr_of_mut!((*me).chunk)
     }
-
-    unsafe fn next(me: *mut TreeChunk) -> *mut TreeChunk {
-        (*TreeChunk::chunk(me)).next.cast()
-    }
-
-    unsafe fn prev(me: *mut TreeChunk) -> *mut TreeChunk {
-        (*TreeChunk::chunk(me)).prev.cast()
-    }
 }
 
 const EXTERN: u32 = 1 << 0;
@@ -1895,14 +2923,6 @@ impl Segment {
     unsafe fn sys_flags(seg: *mut Segment) -> u32 {
         (*seg).flags >> 1
     }
-
-    unsafe fn holds(seg: *mut Segment, addr: *mut u8) -> bool {
-        (*seg).base <= addr && addr < Segment::top(seg)
-    }
-
-    unsafe fn top(seg: *mut Segment) -> *mut u8 {
-        (*seg).base.wrapping_add((*seg).size)
-    }
 }
 
 #[cfg(test)]
@@ -1972,6 +2992,42 @@ mod tests {
         assert_eq!(a.release_checks, usize::MAX);
     }
 
+    #[test]
+    fn validate_checks_configuration() {
+        let mut region = [0u128; 4096];
+        let len = mem::size_of_val(&region);
+        let base = region.as_mut_ptr().cast::<u8>();
+        let (lo, hi) = (base as usize, base as usize + len);
+        let mut a = Dlmalloc::new(crate::NoFallback);
+        unsafe {
+            assert!(a.add_region(base, len));
+            assert!(!a.malloc(100).is_null());
+            assert!(a.validate(lo, hi));
+
+            macro_rules! rejects {
+                ($field:ident = $value:expr) => {{
+                    let saved = mem::replace(&mut a.$field, $value);
+                    assert!(!a.validate(lo, hi), stringify!($field));
+                    a.$field = saved;
+                    assert!(a.validate(lo, hi));
+                }};
+            }
+            rejects!(granularity = 0);
+            rejects!(granularity = 3 * 1024);
+            rejects!(granularity = MIN_GRANULARITY / 2);
+            rejects!(reserve_size = usize::MAX);
+            rejects!(release_checks = 0);
+            rejects!(max_release_check_rate = 0);
+            rejects!(region_len = 16);
+            rejects!(region = base.wrapping_sub(16));
+            rejects!(reserve_base = base);
+            rejects!(reserve_end = base.wrapping_add(len));
+            rejects!(least_addr = Link(ptr::null_mut()));
+            rejects!(least_addr = Link(base.wrapping_add(len + 16)));
+            rejects!(least_addr = Link(base.wrapping_add(len)));
+        }
+    }
+
     #[test]
     fn set_granularity_validates() {
         let mut a = Dlmalloc::new(System::new());
@@ -1,16 +1,25 @@
 use crate::Allocator;
 use core::ptr;
 
+/// System setting for platforms without a known way to allocate memory, all
+/// requests for memory fail.
 pub struct System {
     _priv: (),
 }
 
 impl System {
+    /// Creates a new handle to the system's memory.
     pub const fn new() -> System {
         System { _priv: () }
     }
 }
 
+impl Default for System {
+    fn default() -> System {
+        System::new()
+    }
+}
+
 unsafe impl Allocator for System {
     fn alloc(&self, _size: usize) -> (*mut u8, usize, u32) {
         (ptr::null_mut(), 0, 0)
@@ -40,3 +49,34 @@ unsafe impl Allocator for System {
         1
     }
 }
+
+/// The lock used by `GlobalDlmalloc` on this platform.
+#[cfg(feature = "global")]
+pub type DefaultLock = crate::SpinLock;
+
+#[allow(missing_docs)]
+#[cfg(feature = "global")]
+pub unsafe fn enable_alloc_after_fork() {
+    // platform does not support `fork()` call
+}
+
+/// Returns an identifier for the current thread, or 0 if it isn't known.
+#[cfg(feature = "global")]
+pub fn thread_id() -> usize {
+    0
+}
+
+/// Stops the current thread for good, without allocating.
+///
+/// Panicking could allocate, which re-enters the global allocator while its
+/// lock is held, and there's nothing portable to report `msg` with or to
+/// end the program, so this just spins.
+#[cfg(feature = "global")]
+pub fn abort(_msg: &str) -> ! {
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(feature = "global")]
+pub fn register_fork_handlers() {}
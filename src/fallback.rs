@@ -0,0 +1,118 @@
+use crate::Allocator;
+use core::cmp;
+use core::ptr;
+
+// The bit of the flags of a region which says it came from the secondary
+// allocator. It sits just above `EXTERN`, with the region's own flags moved
+// up to make room, so nested `Fallback`s each take the next bit.
+const SECONDARY: u32 = 1 << 1;
+
+/// An [`Allocator`] which gets memory from a primary allocator, only turning
+/// to a secondary one when the primary returns null.
+///
+/// A typical use is a small pool of fast memory, such as a static SRAM
+/// region, backed by a larger and slower one, such as external RAM or
+/// `mmap`. Either allocator can itself be a `Fallback` for longer chains.
+///
+/// Which allocator provided a region is recorded in the `flags` returned
+/// with it, see [`Allocator::alloc`], and [`Allocator::segment_allocator`]
+/// sends every later call about the region back to that allocator. The
+/// region's own flags are shifted up by one bit to make room, so they lose
+/// their top bit.
+///
+/// As nothing says which allocator a region passed directly to its `free`,
+/// `free_part`, `remap` or similar methods came from, those always fail.
+/// Call them on the allocator returned by `segment_allocator` instead, as
+/// `Dlmalloc` does. For the same reason an allocator wrapping a `Fallback`
+/// has to forward `segment_allocator` to it, or the regions of the
+/// `Fallback` are never freed.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Fallback<P, S> {
+    primary: P,
+    secondary: S,
+}
+
+impl<P, S> Fallback<P, S> {
+    /// Creates an allocator which tries `primary` before `secondary`.
+    pub const fn new(primary: P, secondary: S) -> Fallback<P, S> {
+        Fallback { primary, secondary }
+    }
+
+    /// Returns the allocator which is tried first.
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// Returns the allocator which is used once the primary one fails.
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+}
+
+/// Makes room for the `SECONDARY` bit in the `flags` of a region, setting it
+/// if `secondary`.
+fn tag(flags: u32, secondary: bool) -> u32 {
+    let tag = if secondary { SECONDARY } else { 0 };
+    ((flags & !1) << 1) | tag | (flags & 1)
+}
+
+/// Undoes `tag`, returning whether the region is from the secondary
+/// allocator along with its original flags.
+fn untag(flags: u32) -> (bool, u32) {
+    (flags & SECONDARY != 0, ((flags >> 1) & !1) | (flags & 1))
+}
+
+unsafe impl<P: Allocator, S: Allocator> Allocator for Fallback<P, S> {
+    fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
+        let (ptr, allocated, flags) = self.primary.alloc(size);
+        if !ptr.is_null() {
+            return (ptr, allocated, tag(flags, false));
+        }
+        let (ptr, size, flags) = self.secondary.alloc(size);
+        (ptr, size, tag(flags, true))
+    }
+
+    fn remap(&self, _ptr: *mut u8, _oldsize: usize, _newsize: usize, _can_move: bool) -> *mut u8 {
+        ptr::null_mut()
+    }
+
+    fn free_part(&self, _ptr: *mut u8, _oldsize: usize, _newsize: usize) -> bool {
+        false
+    }
+
+    fn free(&self, _ptr: *mut u8, _size: usize) -> bool {
+        false
+    }
+
+    fn can_release_part(&self, flags: u32) -> bool {
+        // These flags have already had `EXTERN` shifted out.
+        let (secondary, flags) = untag(flags << 1);
+        if secondary {
+            self.secondary.can_release_part(flags >> 1)
+        } else {
+            self.primary.can_release_part(flags >> 1)
+        }
+    }
+
+    fn allocates_zeros(&self) -> bool {
+        self.primary.allocates_zeros() && self.secondary.allocates_zeros()
+    }
+
+    fn page_size(&self) -> usize {
+        cmp::max(self.primary.page_size(), self.secondary.page_size())
+    }
+
+    // Only the primary allocator's reservations are used, as the heap grows
+    // into a reservation before asking for anything else.
+    fn reserve(&self, size: usize) -> (*mut u8, usize, u32) {
+        let (ptr, size, flags) = self.primary.reserve(size);
+        (ptr, size, tag(flags, false))
+    }
+
+    fn segment_allocator(&self, flags: u32) -> &dyn Allocator {
+        match untag(flags) {
+            (false, flags) => self.primary.segment_allocator(flags),
+            (true, flags) => self.secondary.segment_allocator(flags),
+        }
+    }
+}
@@ -1,6 +1,10 @@
-use crate::Dlmalloc;
+use crate::sys::DefaultLock;
+use crate::{Dlmalloc, RawMutex, Stats};
 use core::alloc::{GlobalAlloc, Layout};
+use core::marker::PhantomData;
+use core::ops::Deref;
 use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 
 pub use crate::sys::enable_alloc_after_fork;
 
@@ -8,13 +12,308 @@ pub use crate::sys::enable_alloc_after_fork;
 ///
 /// This API requires the `global` feature is activated, and this type
 /// implements the `GlobalAlloc` trait in the standard library.
+///
+/// All instances of this type share one heap, with the default
+/// configuration. Its methods are those of [`GlobalDlmallocWith`], which
+/// shares the same heap but lets the configuration be picked when declaring
+/// the global allocator.
+///
+/// The heap is guarded by a `pthread_mutex_t` on Unix, an `SRWLOCK` on
+/// Windows, a `WasmLock` on wasm with the `atomics` target feature and a
+/// [`SpinLock`](crate::SpinLock) elsewhere. A different lock can be picked
+/// with the last parameter of [`GlobalDlmallocWith`].
+///
+/// With the `tcache` feature enabled small allocations are served from
+/// per-thread caches of recently freed chunks where possible, only taking the
+/// global lock to refill or flush those caches. The caches are only available
+/// on Linux and macOS; elsewhere the feature has no effect. Programs with
+/// many threads contending on the heap can instead shard it across several
+/// locks with an [`ArenaDlmalloc`](crate::ArenaDlmalloc).
+///
+/// The global lock isn't reentrant. In debug builds a thread trying to take
+/// it while already holding it, for example from a signal handler or a hook
+/// that allocates, aborts the process with a message rather than
+/// deadlocking or corrupting the heap. Code that may run in such a context
+/// can use [`GlobalDlmallocWith::try_alloc`] instead.
+///
+/// On Unix, `pthread_atfork` handlers are registered the first time the heap
+/// is used so that the child of a `fork(2)` can keep allocating even if
+/// another thread was using the heap at the time.
 pub struct GlobalDlmalloc;
 
-static mut DLMALLOC: Dlmalloc = Dlmalloc::new();
+/// [`GlobalDlmalloc`] with its granularity and maximum release check rate,
+/// see [`Dlmalloc::set_granularity`] and
+/// [`Dlmalloc::set_max_release_check_rate`], and the lock guarding the heap
+/// picked through its type:
+///
+/// ```ignore
+/// #[global_allocator]
+/// static A: GlobalDlmallocWith<{ 1 << 20 }, 0, SpinLock> = GlobalDlmallocWith::new();
+/// ```
+///
+/// Every instance of this type, whatever its parameters, and of
+/// `GlobalDlmalloc` shares one heap. The configuration and lock of the
+/// instance used first are applied to the heap, so a program should only use
+/// one. An invalid granularity fails to compile. The heap can also be
+/// reconfigured at runtime with [`GlobalDlmallocWith::configure`].
+pub struct GlobalDlmallocWith<
+    const GRANULARITY: usize = { 64 * 1024 },
+    const RELEASE_RATE: usize = 4095,
+    L: GlobalLock = DefaultLock,
+>(PhantomData<L>);
+
+/// A [`RawMutex`] which can guard the heap of [`GlobalDlmallocWith`].
+///
+/// A generic type can't have a `static` of its own for each lock type, so
+/// each lock provides its one instance for the global heap instead. This is
+/// implemented for every lock shipped by this crate.
+///
+/// # Safety
+///
+/// `instance` must always return the same lock.
+pub unsafe trait GlobalLock: RawMutex + Sync + 'static {
+    /// Returns the instance of this lock guarding the global heap.
+    fn instance() -> &'static Self;
+
+    /// Makes this lock usable again in the child of a `fork(2)`, where it's
+    /// still held by the thread which forked. This releases it by default.
+    ///
+    /// # Safety
+    ///
+    /// Only called on the instance in the child of a fork, while it's held.
+    unsafe fn after_fork(&self) {
+        self.unlock()
+    }
+}
+
+macro_rules! global_lock {
+    ($($(#[$attr:meta])* $lock:ty,)*) => {$(
+        $(#[$attr])*
+        unsafe impl GlobalLock for $lock {
+            fn instance() -> &'static Self {
+                static LOCK: $lock = RawMutex::INIT;
+                &LOCK
+            }
+        }
+    )*};
+}
+
+global_lock! {
+    #[cfg(target_has_atomic = "8")]
+    crate::SpinLock,
+    #[cfg(target_has_atomic = "ptr")]
+    crate::TicketLock,
+    #[cfg(all(not(target_family = "wasm"), target_os = "linux"))]
+    crate::FutexLock,
+    #[cfg(target_os = "windows")]
+    crate::SrwLock,
+    #[cfg(all(target_family = "wasm", target_feature = "atomics"))]
+    crate::WasmLock,
+}
+
+#[cfg(all(
+    not(target_family = "wasm"),
+    any(target_os = "linux", target_os = "macos")
+))]
+unsafe impl GlobalLock for crate::PthreadMutex {
+    fn instance() -> &'static Self {
+        static LOCK: crate::PthreadMutex = RawMutex::INIT;
+        &LOCK
+    }
+
+    unsafe fn after_fork(&self) {
+        self.reinit()
+    }
+}
+
+pub(crate) static mut DLMALLOC: Dlmalloc = Dlmalloc::new();
+
+/// Whether the configuration of a `GlobalDlmallocWith` has been applied to
+/// `DLMALLOC` yet.
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+
+/// How to use the lock guarding `DLMALLOC`, whichever `GlobalLock` it is.
+struct LockOps {
+    lock: fn(),
+    unlock: unsafe fn(),
+    after_fork: unsafe fn(),
+}
+
+/// The `LockOps` of the lock guarding `DLMALLOC`, picked by the first
+/// `GlobalDlmallocWith` used, or null before then.
+static LOCK: AtomicPtr<LockOps> = AtomicPtr::new(ptr::null_mut());
+
+/// The `sys::thread_id` of the thread holding `LOCK`, or 0.
+static OWNER: AtomicUsize = AtomicUsize::new(0);
+
+impl Deref for GlobalDlmalloc {
+    type Target = GlobalDlmallocWith;
+
+    fn deref(&self) -> &GlobalDlmallocWith {
+        &GlobalDlmallocWith(PhantomData)
+    }
+}
 
 unsafe impl GlobalAlloc for GlobalDlmalloc {
     #[inline]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        (**self).alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        (**self).dealloc(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        (**self).alloc_zeroed(layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        (**self).realloc(ptr, layout, new_size)
+    }
+}
+
+impl<const GRANULARITY: usize, const RELEASE_RATE: usize, L: GlobalLock>
+    GlobalDlmallocWith<GRANULARITY, RELEASE_RATE, L>
+{
+    /// Creates a new instance, to be declared as the global allocator.
+    pub const fn new() -> Self {
+        GlobalDlmallocWith(PhantomData)
+    }
+
+    /// Like `GlobalAlloc::alloc`, but returns a null pointer instead of
+    /// deadlocking if the current thread already holds the global lock.
+    ///
+    /// On platforms where the current thread can't be identified this
+    /// behaves exactly like `alloc`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> *mut u8 {
+        if holds_lock() {
+            return ptr::null_mut();
+        }
+        self.alloc(layout)
+    }
+
+    /// Takes the global lock and gives unused memory back to the system, see
+    /// [`Dlmalloc::trim`].
+    pub fn trim(&self, pad: usize) -> bool {
+        Self::init();
+        unsafe {
+            let _guard = lock();
+            let dlmalloc = ptr::addr_of_mut!(DLMALLOC);
+            (*dlmalloc).trim(pad)
+        }
+    }
+
+    /// Takes the global lock and purges free memory, see
+    /// [`Dlmalloc::purge`].
+    ///
+    /// Memory cached by the `tcache` feature isn't free as far as the heap
+    /// is concerned, so it isn't purged.
+    pub fn purge(&self, max_bytes: usize) -> usize {
+        Self::init();
+        unsafe {
+            let _guard = lock();
+            let dlmalloc = ptr::addr_of_mut!(DLMALLOC);
+            (*dlmalloc).purge(max_bytes)
+        }
+    }
+
+    /// Takes the global lock and purges memory which has been free for a
+    /// while, see [`Dlmalloc::decay`]. The decay time can be set with
+    /// [`GlobalDlmallocWith::configure`].
+    pub fn decay(&self, now: u64) -> usize {
+        Self::init();
+        unsafe {
+            let _guard = lock();
+            let dlmalloc = ptr::addr_of_mut!(DLMALLOC);
+            (*dlmalloc).decay(now)
+        }
+    }
+
+    /// Takes the global lock and returns statistics about the global heap,
+    /// see [`Dlmalloc::stats`].
+    pub fn stats(&self) -> Stats {
+        Self::init();
+        unsafe {
+            let _guard = lock();
+            let dlmalloc = ptr::addr_of!(DLMALLOC);
+            (*dlmalloc).stats()
+        }
+    }
+
+    /// Runs `f` with exclusive access to the global heap, for example to
+    /// change its granularity with [`Dlmalloc::set_granularity`].
+    ///
+    /// The global lock is held while `f` runs, so `f` must not allocate
+    /// through the global allocator.
+    pub fn configure<R>(&self, f: impl FnOnce(&mut Dlmalloc) -> R) -> R {
+        Self::init();
+        unsafe {
+            let _guard = lock();
+            let dlmalloc = ptr::addr_of_mut!(DLMALLOC);
+            f(&mut *dlmalloc)
+        }
+    }
+
+    /// Applies `GRANULARITY`, `RELEASE_RATE` and `L` to the heap unless an
+    /// instance has already done so.
+    #[inline]
+    fn init() {
+        const {
+            assert!(
+                GRANULARITY.is_power_of_two() && GRANULARITY >= 2 * core::mem::size_of::<usize>(),
+                "the granularity must be a power of two and at least the malloc alignment"
+            );
+        }
+        if !CONFIGURED.load(Ordering::Acquire) {
+            Self::init_slow();
+        }
+    }
+
+    #[cold]
+    fn init_slow() {
+        pick_lock::<L>();
+        unsafe {
+            let _guard = lock();
+            if CONFIGURED.load(Ordering::Relaxed) {
+                return;
+            }
+            let dlmalloc = &mut *ptr::addr_of_mut!(DLMALLOC);
+            dlmalloc.set_granularity(GRANULARITY);
+            dlmalloc.set_max_release_check_rate(RELEASE_RATE);
+            CONFIGURED.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl<const GRANULARITY: usize, const RELEASE_RATE: usize, L: GlobalLock> Default
+    for GlobalDlmallocWith<GRANULARITY, RELEASE_RATE, L>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<const GRANULARITY: usize, const RELEASE_RATE: usize, L: GlobalLock> GlobalAlloc
+    for GlobalDlmallocWith<GRANULARITY, RELEASE_RATE, L>
+{
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Self::init();
+        #[cfg(feature = "tcache")]
+        {
+            let ptr = crate::tcache::alloc(&layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
         let _guard = lock();
         let dlmalloc = ptr::addr_of_mut!(DLMALLOC);
         (*dlmalloc).malloc(layout.size(), layout.align())
@@ -22,6 +321,12 @@ unsafe impl GlobalAlloc for GlobalDlmalloc {
 
     #[inline]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "tcache")]
+        {
+            if crate::tcache::dealloc(ptr, &layout) {
+                return;
+            }
+        }
         let _guard = lock();
         let dlmalloc = ptr::addr_of_mut!(DLMALLOC);
         (*dlmalloc).free(ptr, layout.size(), layout.align())
@@ -29,6 +334,15 @@ unsafe impl GlobalAlloc for GlobalDlmalloc {
 
     #[inline]
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Self::init();
+        #[cfg(feature = "tcache")]
+        {
+            let ptr = crate::tcache::alloc(&layout);
+            if !ptr.is_null() {
+                ptr::write_bytes(ptr, 0, layout.size());
+                return ptr;
+            }
+        }
         let _guard = lock();
         let dlmalloc = ptr::addr_of_mut!(DLMALLOC);
         (*dlmalloc).calloc(layout.size(), layout.align())
@@ -42,15 +356,93 @@ unsafe impl GlobalAlloc for GlobalDlmalloc {
     }
 }
 
-unsafe fn lock() -> impl Drop {
-    crate::sys::acquire_global_lock();
+pub(crate) unsafe fn lock() -> impl Drop {
+    acquire_global_lock();
 
     struct Guard;
     impl Drop for Guard {
         fn drop(&mut self) {
-            crate::sys::release_global_lock()
+            unsafe { release_global_lock() }
         }
     }
 
     Guard
 }
+
+pub(crate) fn acquire_global_lock() {
+    if cfg!(debug_assertions) && holds_lock() {
+        crate::sys::abort(
+            "fatal error: the global allocator was re-entered by a thread \
+             already holding its lock",
+        );
+    }
+    (lock_ops().lock)();
+    OWNER.store(crate::sys::thread_id(), Ordering::Relaxed);
+}
+
+pub(crate) unsafe fn release_global_lock() {
+    OWNER.store(0, Ordering::Relaxed);
+    (lock_ops().unlock)()
+}
+
+/// Puts the global lock back in its unlocked state in the child of a fork.
+#[cfg(all(
+    not(target_family = "wasm"),
+    any(target_os = "linux", target_os = "macos")
+))]
+pub(crate) unsafe fn reinit_global_lock() {
+    OWNER.store(0, Ordering::Relaxed);
+    (lock_ops().after_fork)();
+}
+
+/// Returns the `LockOps` of the lock guarding `DLMALLOC`, which is the
+/// platform's unless a `GlobalDlmallocWith` picked another first.
+#[inline]
+fn lock_ops() -> &'static LockOps {
+    let ops = LOCK.load(Ordering::Acquire);
+    if ops.is_null() {
+        return pick_lock::<DefaultLock>();
+    }
+    unsafe { &*ops }
+}
+
+/// Makes `L` guard `DLMALLOC` unless a lock has already been picked,
+/// returning the `LockOps` of the lock which was.
+///
+/// The `fork(2)` handlers are registered before any lock is picked, so they
+/// are in place before the heap is first locked.
+#[cold]
+fn pick_lock<L: GlobalLock>() -> &'static LockOps {
+    crate::sys::register_fork_handlers();
+    fn lock<L: GlobalLock>() {
+        L::instance().lock()
+    }
+    unsafe fn unlock<L: GlobalLock>() {
+        L::instance().unlock()
+    }
+    unsafe fn after_fork<L: GlobalLock>() {
+        L::instance().after_fork()
+    }
+
+    let ops: &'static LockOps = const {
+        &LockOps {
+            lock: lock::<L>,
+            unlock: unlock::<L>,
+            after_fork: after_fork::<L>,
+        }
+    };
+    let new = ops as *const LockOps as *mut LockOps;
+    match LOCK.compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => ops,
+        Err(picked) => unsafe { &*picked },
+    }
+}
+
+/// Returns whether the current thread is known to hold the global lock.
+///
+/// Only the owning thread ever stores its own id in `OWNER`, so a relaxed
+/// load is enough for a thread to see whether that's itself.
+fn holds_lock() -> bool {
+    let me = crate::sys::thread_id();
+    me != 0 && OWNER.load(Ordering::Relaxed) == me
+}
@@ -0,0 +1,127 @@
+//! Heaps which live entirely inside a caller-provided region, bookkeeping
+//! included, so the region can be saved and reopened later.
+
+use crate::{Dlmalloc, NoFallback};
+use core::mem;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"DLMALLOC");
+
+// Bumped whenever the layout of an image changes in a way `size` wouldn't
+// catch, such as a change to what a field of the heap means.
+const VERSION: u32 = 2;
+
+/// The start of a heap image, followed by the memory the heap allocates from.
+#[repr(C)]
+struct Header {
+    magic: u64,
+    version: u32,
+    // `size_of::<Header>()`, so an image from a build with a different
+    // layout, including one for a different pointer width, is rejected.
+    size: u32,
+    len: usize,
+    heap: Dlmalloc<NoFallback>,
+}
+
+impl Header {
+    /// Checks that `len` bytes at `base` are big enough and suitably aligned
+    /// for a header, returning it if so.
+    fn at(base: *mut u8, len: usize) -> Option<*mut Header> {
+        if base.is_null()
+            || (base as usize) % mem::align_of::<Header>() != 0
+            || len < mem::size_of::<Header>()
+            || (base as usize).checked_add(len).is_none()
+        {
+            return None;
+        }
+        Some(base.cast())
+    }
+}
+
+impl Dlmalloc<NoFallback> {
+    /// Creates a new heap in the `len` bytes at `base`, keeping its own
+    /// bookkeeping at the start of the region and allocating from the rest.
+    ///
+    /// Everything about the heap lives in the region, so saving the region,
+    /// for example by having it be a memory-mapped file, saves the heap and
+    /// everything allocated from it. [`Dlmalloc::attach`] opens it again
+    /// later, and [`Dlmalloc::set_root`] records where to find the data in it.
+    ///
+    /// The heap's bookkeeping records offsets from the header rather than
+    /// addresses, so the image works wherever it's mapped, including at
+    /// different addresses in several processes at once, as long as they
+    /// take turns using it.
+    ///
+    /// Returns `None` if `base` isn't aligned to a pointer or the region is
+    /// too small.
+    ///
+    /// # Safety
+    ///
+    /// The region must be valid for reads and writes for `'a` and must not be
+    /// used for anything else in the meantime.
+    pub unsafe fn create_image<'a>(
+        base: *mut u8,
+        len: usize,
+    ) -> Option<&'a mut Dlmalloc<NoFallback>> {
+        let header = Header::at(base, len)?;
+        header.write(Header {
+            magic: MAGIC,
+            version: VERSION,
+            size: mem::size_of::<Header>() as u32,
+            len,
+            heap: Dlmalloc::new_with_allocator(NoFallback),
+        });
+        let heap = &mut (*header).heap;
+        heap.0.set_relative(true);
+        let start = base.add(mem::size_of::<Header>());
+        if !heap.add_region(start, len - mem::size_of::<Header>()) {
+            (*header).magic = 0;
+            return None;
+        }
+        Some(heap)
+    }
+
+    /// Opens the heap image made by [`Dlmalloc::create_image`] in the `len`
+    /// bytes at `base`, picking up where it was left.
+    ///
+    /// The image may be at a different address than when it was created, or
+    /// be attached at the same time elsewhere, as its heap only records
+    /// offsets, and [`Dlmalloc::root`] returns the root at its address in
+    /// this mapping. Pointers stored in allocations aren't known to the heap,
+    /// so they need to be offsets from `base` as well to be usable wherever
+    /// the image is mapped.
+    ///
+    /// Before anything is changed the image is checked: it must start with
+    /// the header `create_image` writes, from a build of this crate with the
+    /// same layout, for a region of the same length, and the heap in it must
+    /// be consistent. `None` is returned otherwise.
+    ///
+    /// The heap changes the image in place without any journaling, so an
+    /// image saved in the middle of a call into the heap, or left behind by
+    /// a crash during one, is likely to be rejected. The checks aren't a
+    /// defense against an image crafted to pass them.
+    ///
+    /// # Safety
+    ///
+    /// The region must be valid for reads and writes for `'a` and must not be
+    /// used for anything else in the meantime, other than through the
+    /// returned heap and its allocations.
+    pub unsafe fn attach<'a>(base: *mut u8, len: usize) -> Option<&'a mut Dlmalloc<NoFallback>> {
+        let header = Header::at(base, len)?;
+        if (*header).magic != MAGIC
+            || (*header).version != VERSION
+            || (*header).size != mem::size_of::<Header>() as u32
+            || (*header).len != len
+        {
+            return None;
+        }
+        let heap = &mut (*header).heap;
+        let end = base as usize + len;
+        if !heap
+            .0
+            .validate(base.add(mem::size_of::<Header>()) as usize, end)
+        {
+            return None;
+        }
+        Some(heap)
+    }
+}
@@ -12,20 +12,65 @@
 //! testing this crate.
 
 #![allow(dead_code)]
+// `usize::is_multiple_of` is too new for some of the toolchains building this.
+#![allow(clippy::manual_is_multiple_of)]
 #![no_std]
 #![deny(missing_docs)]
 #![cfg_attr(target_arch = "wasm64", feature(simd_wasm64))]
+#![cfg_attr(
+    all(target_family = "wasm", target_feature = "atomics"),
+    feature(stdarch_wasm_atomic_wait, thread_local)
+)]
 
 use core::cmp;
+use core::mem::MaybeUninit;
 use core::ptr;
-use sys::System;
 
+#[cfg(target_has_atomic = "ptr")]
+pub use self::arena::ArenaDlmalloc;
+pub use self::dlmalloc::Segments;
+pub use self::fallback::Fallback;
 #[cfg(feature = "global")]
-pub use self::global::{enable_alloc_after_fork, GlobalDlmalloc};
+pub use self::global::{enable_alloc_after_fork, GlobalDlmalloc, GlobalDlmallocWith, GlobalLock};
+pub use self::lock::RawMutex;
+#[cfg(target_has_atomic = "8")]
+pub use self::lock::SpinLock;
+#[cfg(target_has_atomic = "ptr")]
+pub use self::lock::TicketLock;
+pub use self::locked::LockedDlmalloc;
+pub use self::owned::OwnedDlmalloc;
+#[cfg(all(
+    not(target_family = "wasm"),
+    any(target_os = "linux", target_os = "macos")
+))]
+pub use self::sys::FileBacked;
+#[cfg(all(not(target_family = "wasm"), target_os = "linux"))]
+pub use self::sys::FutexLock;
+#[cfg(all(not(target_family = "wasm"), target_os = "linux"))]
+pub use self::sys::HugePageSystem;
+#[cfg(all(
+    not(target_family = "wasm"),
+    any(target_os = "linux", target_os = "macos")
+))]
+pub use self::sys::PthreadMutex;
+#[cfg(target_os = "windows")]
+pub use self::sys::SrwLock;
+pub use self::sys::System;
+#[cfg(all(target_family = "wasm", target_feature = "atomics"))]
+pub use self::sys::WasmLock;
 
+#[cfg(target_has_atomic = "ptr")]
+mod arena;
 mod dlmalloc;
+mod fallback;
 #[cfg(feature = "global")]
 mod global;
+mod image;
+mod lock;
+mod locked;
+mod owned;
+#[cfg(feature = "tcache")]
+mod tcache;
 
 /// In order for this crate to efficiently manage memory, it needs a way to communicate with the
 /// underlying platform. This `Allocator` trait provides an interface for this communication.
@@ -64,13 +109,176 @@ pub unsafe trait Allocator: Send {
 
     /// Returns the page size. Must be a power of two
     fn page_size(&self) -> usize;
+
+    /// Reserves a region of address space of at least `size` bytes without
+    /// making any of it usable yet. Returns a triple like `Allocator::alloc`.
+    ///
+    /// Parts of the region are made usable with `Allocator::commit`, from
+    /// the start of the region upwards, so a heap can keep growing within
+    /// one contiguous segment. The whole region, committed or not, is later
+    /// released with `Allocator::free`, and its uncommitted end may be
+    /// released with `Allocator::free_part`.
+    ///
+    /// The default implementation returns `std::ptr::null_mut()`, meaning
+    /// reservations aren't supported and all memory comes from
+    /// `Allocator::alloc`.
+    fn reserve(&self, size: usize) -> (*mut u8, usize, u32) {
+        let _ = size;
+        (ptr::null_mut(), 0, 0)
+    }
+
+    /// Makes the `size` bytes at `ptr`, which lie within a region returned by
+    /// `Allocator::reserve`, readable and writable. Returns `true` iff the
+    /// operation succeeded.
+    fn commit(&self, ptr: *mut u8, size: usize) -> bool {
+        let _ = (ptr, size);
+        false
+    }
+
+    /// Gives the physical memory behind the `size` bytes at `ptr`, which
+    /// were committed with `Allocator::commit`, back to the system while
+    /// keeping the address space reserved. Returns `true` iff the operation
+    /// succeeded, in which case the memory must be committed again before
+    /// it's used.
+    fn decommit(&self, ptr: *mut u8, size: usize) -> bool {
+        let _ = (ptr, size);
+        false
+    }
+
+    /// Tells the system that the contents of the `size` bytes at `ptr`, which
+    /// are page aligned and lie within a region returned by `Allocator::alloc`
+    /// or committed with `Allocator::commit`, are no longer needed, so it can
+    /// reclaim the physical memory behind them. The memory must remain usable
+    /// afterwards, although its contents are unspecified. Returns `true` iff
+    /// the operation succeeded.
+    ///
+    /// The default implementation does nothing and returns `false`.
+    fn purge(&self, ptr: *mut u8, size: usize) -> bool {
+        let _ = (ptr, size);
+        false
+    }
+
+    /// Returns the allocator which provided a region, given the `flags`
+    /// returned along with it by `Allocator::alloc` or `Allocator::reserve`.
+    ///
+    /// Every call about a region once it's been handed out, that is
+    /// `Allocator::free`, `Allocator::free_part`, `Allocator::commit`,
+    /// `Allocator::decommit` and `Allocator::purge`, is made on the returned
+    /// allocator. Allocators which combine others, like [`Fallback`], use
+    /// this to send each call to the one which provided the region.
+    ///
+    /// The default implementation returns `self`. An allocator wrapping
+    /// another one must forward this method to it if the wrapped allocator
+    /// overrides it. Otherwise the heap calls the wrapper about every region
+    /// instead, and a wrapped [`Fallback`], whose own `free`, `free_part`
+    /// and `remap` always fail, silently leaks all of its regions.
+    fn segment_allocator(&self, flags: u32) -> &dyn Allocator
+    where
+        Self: Sized,
+    {
+        let _ = flags;
+        self
+    }
+}
+
+/// An [`Allocator`] which never provides any memory.
+///
+/// This is useful for heaps which should only ever use memory handed to them
+/// directly, such as one created with [`Dlmalloc::with_region_and_allocator`]
+/// on a target without an operating system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoFallback;
+
+unsafe impl Allocator for NoFallback {
+    fn alloc(&self, _size: usize) -> (*mut u8, usize, u32) {
+        (ptr::null_mut(), 0, 0)
+    }
+
+    fn remap(&self, _ptr: *mut u8, _oldsize: usize, _newsize: usize, _can_move: bool) -> *mut u8 {
+        ptr::null_mut()
+    }
+
+    fn free_part(&self, _ptr: *mut u8, _oldsize: usize, _newsize: usize) -> bool {
+        false
+    }
+
+    fn free(&self, _ptr: *mut u8, _size: usize) -> bool {
+        false
+    }
+
+    fn can_release_part(&self, _flags: u32) -> bool {
+        false
+    }
+
+    fn allocates_zeros(&self) -> bool {
+        false
+    }
+
+    fn page_size(&self) -> usize {
+        1
+    }
+}
+
+/// Statistics about the memory managed by a [`Dlmalloc`], as returned by
+/// [`Dlmalloc::stats`].
+///
+/// This is the equivalent of `mallinfo` in the C implementation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Number of bytes currently obtained from the system allocator.
+    pub footprint: usize,
+    /// Maximum number of bytes ever obtained from the system allocator.
+    pub max_footprint: usize,
+    /// Number of bytes in use by allocations, including per-chunk overhead.
+    pub used: usize,
+    /// Number of bytes in free chunks, including the top chunk.
+    pub free: usize,
+    /// Number of free chunks, including the top chunk.
+    pub free_chunks: usize,
+    /// Number of bytes in the top chunk, an upper bound on what
+    /// [`Dlmalloc::trim`] can release back to the system.
+    pub releasable: usize,
+}
+
+/// A contiguous region of memory making up part of a [`Dlmalloc`]'s heap, as
+/// returned by [`Dlmalloc::segments`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SegmentInfo {
+    /// Start of the region.
+    pub base: *mut u8,
+    /// Size of the region in bytes.
+    pub size: usize,
+    /// The flags [`Allocator::alloc`] returned for the region, or just the
+    /// extern bit for a region provided by the caller.
+    pub flags: u32,
+    /// Whether the region was provided by the caller, through
+    /// [`Dlmalloc::with_region_and_allocator`] or [`Dlmalloc::add_region`],
+    /// rather than obtained from the system allocator. Such regions are never
+    /// handed back to it.
+    pub is_extern: bool,
+    /// Whether [`Dlmalloc::trim`] may give part of the region back to the
+    /// system allocator.
+    pub releasable: bool,
+}
+
+/// A point in a heap's history returned by [`Dlmalloc::checkpoint`].
+#[derive(Debug)]
+#[must_use = "a checkpoint must be ended with `rollback` or `commit`"]
+pub struct Checkpoint {
+    len: usize,
+    depth: usize,
 }
 
 /// An allocator instance
 ///
 /// Instances of this type are used to allocate blocks of memory. For best
-/// results only use one of these. Currently doesn't implement `Drop` to release
-/// lingering memory back to the OS. That may happen eventually though!
+/// results only use one of these. This type doesn't implement `Drop`, so
+/// lingering memory isn't released back to the OS unless
+/// [`Dlmalloc::destroy`] is called; wrap it in an [`OwnedDlmalloc`] to do
+/// that automatically when it's dropped.
+#[repr(transparent)]
 pub struct Dlmalloc<A = System>(dlmalloc::Dlmalloc<A>);
 
 cfg_if::cfg_if! {
@@ -97,6 +305,14 @@ impl Dlmalloc<System> {
     pub const fn new() -> Dlmalloc<System> {
         Dlmalloc(dlmalloc::Dlmalloc::new(System::new()))
     }
+
+    /// Creates a new allocator which carves allocations out of `region`,
+    /// falling back to the system for more memory once it's full.
+    ///
+    /// See [`Dlmalloc::with_region_and_allocator`].
+    pub const fn with_region(region: &'static mut [MaybeUninit<u8>]) -> Dlmalloc<System> {
+        Dlmalloc::with_region_and_allocator(region, System::new())
+    }
 }
 
 impl<A> Dlmalloc<A> {
@@ -105,6 +321,33 @@ impl<A> Dlmalloc<A> {
         Dlmalloc(dlmalloc::Dlmalloc::new(sys_allocator))
     }
 
+    /// Creates a new allocator which carves allocations out of `region`,
+    /// only asking `sys_allocator` for more memory once it's full.
+    ///
+    /// This is the equivalent of C's `create_mspace_with_base`. The region
+    /// is never handed to `sys_allocator`, not even by
+    /// [`Dlmalloc::destroy`]. Pass [`NoFallback`] as the allocator for a heap
+    /// which only ever uses `region`:
+    ///
+    /// ```ignore
+    /// static mut HEAP: [MaybeUninit<u8>; 64 * 1024] = [MaybeUninit::uninit(); 64 * 1024];
+    ///
+    /// #[global_allocator]
+    /// static A: LockedDlmalloc<NoFallback, SpinLock> = LockedDlmalloc::new(
+    ///     Dlmalloc::with_region_and_allocator(unsafe { &mut *ptr::addr_of_mut!(HEAP) }, NoFallback),
+    /// );
+    /// ```
+    ///
+    /// A `region` too small to hold any allocation is ignored.
+    pub const fn with_region_and_allocator(
+        region: &'static mut [MaybeUninit<u8>],
+        sys_allocator: A,
+    ) -> Dlmalloc<A> {
+        let len = region.len();
+        let base = region.as_mut_ptr().cast::<u8>();
+        Dlmalloc(dlmalloc::Dlmalloc::with_region(sys_allocator, base, len))
+    }
+
     /// Sets the maximum number of large-chunk frees between periodic
     /// release-unused-segments passes. A value of `0` disables the pass.
     ///
@@ -127,7 +370,10 @@ impl<A> Dlmalloc<A> {
     /// Unlike C dlmalloc's `mallopt(M_GRANULARITY, ...)`, which rejects
     /// sub-page values, this accepts any pow-of-two >= the malloc alignment.
     /// Sub-page granularity is intentionally allowed for embedded targets
-    /// that need tightly-packed allocations.
+    /// that need tightly-packed allocations. Memory is still requested from
+    /// and returned to the system allocator in whole pages, as reported by
+    /// [`Allocator::page_size`], so a granularity below the page size only
+    /// makes a difference with allocators reporting small pages.
     ///
     /// For best results call this before the first allocation; existing
     /// segments retain their original alignment.
@@ -147,11 +393,33 @@ impl<A> Dlmalloc<A> {
     /// ```
     ///
     /// `Dlmalloc` is `Send` but not `Sync`, so it cannot be placed directly
-    /// in a `static`; use `GlobalDlmalloc` (behind the `global` feature)
-    /// when a `static` allocator is needed.
+    /// in a `static`; wrap it in a [`LockedDlmalloc`], or use
+    /// `GlobalDlmalloc` (behind the `global` feature), when a `static`
+    /// allocator is needed.
     pub const fn set_granularity(&mut self, granularity: usize) -> bool {
         self.0.set_granularity(granularity)
     }
+
+    /// Sets how long a block must stay free before [`Dlmalloc::decay`]
+    /// purges it, in the units of the clock passed to `decay`. Defaults to
+    /// `0`, which purges every free block.
+    pub const fn set_decay_time(&mut self, time: u64) {
+        self.0.set_decay_time(time)
+    }
+
+    /// Sets how much address space to reserve at a time when the system
+    /// allocator supports [`Allocator::reserve`]. Defaults to `0`, which
+    /// disables reservations, so that all memory is obtained with
+    /// [`Allocator::alloc`].
+    ///
+    /// The heap then grows by committing more of the reservation in
+    /// granularity-sized steps, so it stays one contiguous segment until the
+    /// reservation is used up, and [`Dlmalloc::trim`] decommits memory
+    /// without giving up the address space. A larger reservation costs
+    /// address space but no memory.
+    pub const fn set_reserve_size(&mut self, size: usize) {
+        self.0.set_reserve_size(size)
+    }
 }
 
 impl<A: Allocator> Dlmalloc<A> {
@@ -278,6 +546,188 @@ impl<A: Allocator> Dlmalloc<A> {
         self.0.trim(pad)
     }
 
+    /// Gives the physical memory behind large free blocks back to the
+    /// system while keeping their address space, stopping once `max_bytes`
+    /// have been purged. Returns the number of bytes purged.
+    ///
+    /// Unlike [`Dlmalloc::trim`], which can only release memory at the end
+    /// of the heap or whole unused regions, this reaches free blocks
+    /// anywhere, which is what lets a heap's resident size come back down
+    /// after a spike in usage even while some allocations from the spike
+    /// remain. Only whole pages inside a free block are purged, using
+    /// [`Allocator::purge`], and blocks which have already been purged and
+    /// not reused since are skipped. Regions provided by the caller, through
+    /// [`Dlmalloc::with_region_and_allocator`] or [`Dlmalloc::add_region`],
+    /// are never purged.
+    ///
+    /// This walks every chunk in the heap, so it takes time proportional to
+    /// the number of live and free chunks. Pass `usize::MAX` to purge
+    /// everything, or a smaller limit to spread the cost over several calls,
+    /// for example from an idle loop. To only purge memory which has been
+    /// free for a while use [`Dlmalloc::decay`] instead.
+    pub fn purge(&mut self, max_bytes: usize) -> usize {
+        unsafe { self.0.purge(max_bytes) }
+    }
+
+    /// Purges the free blocks which have stayed free for at least the decay
+    /// time, see [`Dlmalloc::set_decay_time`], as of `now`. Returns the
+    /// number of bytes purged.
+    ///
+    /// This crate has no clock of its own, so `now` comes from the caller
+    /// and may count anything which only goes up, such as milliseconds since
+    /// startup or the number of calls to this method. Calling this
+    /// periodically gradually returns memory left over from a spike in usage
+    /// to the system, without giving back memory which is about to be reused
+    /// and without adding any cost to [`Dlmalloc::free`].
+    ///
+    /// A block's age is counted from the first call to this method which
+    /// sees it free, so it may be purged up to two calling periods later
+    /// than the decay time alone would suggest.
+    ///
+    /// The age of a block which was freed, allocated and then freed again
+    /// without a call to this method in between may be overestimated.
+    ///
+    /// Like [`Dlmalloc::purge`] this walks every chunk in the heap.
+    pub fn decay(&mut self, now: u64) -> usize {
+        unsafe { self.0.decay(now) }
+    }
+
+    /// Returns statistics about the memory managed by this allocator.
+    ///
+    /// This walks every chunk in the heap, so it takes time proportional to
+    /// the number of live and free chunks.
+    pub fn stats(&self) -> Stats {
+        unsafe { self.0.stats() }
+    }
+
+    /// Returns an iterator over the regions of memory making up this heap.
+    ///
+    /// Every byte the heap manages, including every allocation it has handed
+    /// out, lies in exactly one of these regions: large allocations aren't
+    /// mapped separately as they are in the C implementation, so this always
+    /// lists everything [`Dlmalloc::destroy`] would free. Regions obtained from
+    /// the system allocator may later be given back by [`Dlmalloc::trim`] or
+    /// [`Dlmalloc::free`], so the list is only accurate until the heap is next
+    /// used.
+    pub fn segments(&self) -> Segments<'_, A> {
+        self.0.segments()
+    }
+
+    /// Donates the `len` bytes at `base` to this heap, which can then carve
+    /// allocations out of them.
+    ///
+    /// The region doesn't need to be next to any memory the heap already
+    /// has, which makes this useful for memory that only becomes usable
+    /// later, such as an external RAM bank after its controller has been
+    /// initialized. Like a region passed to
+    /// [`Dlmalloc::with_region_and_allocator`] it's never handed to the
+    /// system allocator, not even by [`Dlmalloc::destroy`].
+    ///
+    /// Returns `false`, leaving the heap unchanged, if the region is too
+    /// small to hold any allocation.
+    ///
+    /// # Safety
+    ///
+    /// The region must be valid for reads and writes, must not overlap any
+    /// memory this heap already manages and must not be used for anything
+    /// else until this heap has been destroyed or dropped.
+    pub unsafe fn add_region(&mut self, base: *mut u8, len: usize) -> bool {
+        self.0.add_region(base, len)
+    }
+
+    /// Returns the pointer last passed to [`Dlmalloc::set_root`], or null.
+    pub fn root(&self) -> *mut u8 {
+        self.0.root()
+    }
+
+    /// Stores a pointer alongside the heap's own bookkeeping.
+    ///
+    /// It's how a heap kept in a file finds its data again after being
+    /// reopened: point it at an allocation from this heap holding the rest,
+    /// such as the top of an object graph. In a heap image it's stored as an
+    /// offset, so [`Dlmalloc::root`] returns it at its address in whichever
+    /// mapping the image was attached from.
+    pub fn set_root(&mut self, root: *mut u8) {
+        self.0.set_root(root)
+    }
+
+    /// Frees every allocation made from this allocator at once, without
+    /// giving any memory back to the system.
+    ///
+    /// Afterwards the heap behaves as if everything had been freed
+    /// individually, except that it's much faster: each region obtained from
+    /// the system becomes a single free block again. This is useful for
+    /// arena-style "free everything" patterns, such as a per-frame or
+    /// per-request heap, without unmapping and remapping memory each time.
+    ///
+    /// # Safety
+    ///
+    /// No allocation made from this allocator before the call may be used,
+    /// freed or reallocated afterwards. Any active checkpoints are ended and
+    /// must not be passed to [`Dlmalloc::rollback`] or [`Dlmalloc::commit`].
+    pub unsafe fn reset(&mut self) {
+        self.0.reset()
+    }
+
+    /// Starts a checkpoint which [`Dlmalloc::rollback`] can later return the
+    /// heap to.
+    ///
+    /// While a checkpoint is active every allocation is recorded in a log,
+    /// itself allocated from this heap, so that rolling back can free
+    /// everything allocated since the checkpoint that's still live.
+    /// Allocations made before the checkpoint are kept. Freeing an allocation
+    /// made since the checkpoint only clears its entry, as the log is never
+    /// compacted, so the log grows by a word with every allocation made while
+    /// the checkpoint is active, including ones already freed, until it's
+    /// ended. This is best suited to short-lived scopes such as a single
+    /// parse.
+    ///
+    /// Checkpoints nest and must be ended, by [`Dlmalloc::rollback`] or
+    /// [`Dlmalloc::commit`], in the reverse order they were started. Ending
+    /// one also ends any checkpoints started after it. [`Dlmalloc::reset`]
+    /// ends all of them.
+    ///
+    /// An allocation made before a checkpoint which is moved by a
+    /// reallocation while the checkpoint is active counts as a new
+    /// allocation.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        let (len, depth) = self.0.checkpoint();
+        Checkpoint { len, depth }
+    }
+
+    /// Frees every allocation made since `checkpoint` which is still live,
+    /// and ends `checkpoint`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `checkpoint` was already ended, for example because an
+    /// earlier checkpoint was ended.
+    ///
+    /// # Safety
+    ///
+    /// `checkpoint` must come from this allocator, and none of the
+    /// allocations made since it may be used afterwards.
+    pub unsafe fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.0.rollback(checkpoint.len, checkpoint.depth)
+    }
+
+    /// Ends `checkpoint`, keeping the allocations made since it.
+    ///
+    /// If an earlier checkpoint is still active, those allocations now belong
+    /// to it and rolling it back frees them as well.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `checkpoint` was already ended, for example because an
+    /// earlier checkpoint was ended.
+    ///
+    /// # Safety
+    ///
+    /// `checkpoint` must come from this allocator.
+    pub unsafe fn commit(&mut self, checkpoint: Checkpoint) {
+        self.0.commit(checkpoint.len, checkpoint.depth)
+    }
+
     /// Releases all allocations in this allocator back to the system,
     /// consuming self and preventing further use.
     ///
@@ -327,7 +777,12 @@ impl<A: Allocator> Dlmalloc<A> {
     /// the deallocation methods on the same allocator instance.
     #[inline]
     pub unsafe fn c_malloc(&mut self, size: usize) -> *mut u8 {
-        self.0.malloc(size)
+        if !self.0.reserve_log() {
+            return ptr::null_mut();
+        }
+        let ptr = self.0.malloc(size.saturating_add(self.0.log_padding()));
+        self.0.track(ptr);
+        ptr
     }
 
     /// Allocates `size` bytes aligned to at least `align` bytes.
@@ -349,7 +804,14 @@ impl<A: Allocator> Dlmalloc<A> {
         if align <= self.0.malloc_alignment() {
             self.c_malloc(size)
         } else {
-            self.0.memalign(align, size)
+            if !self.0.reserve_log() {
+                return ptr::null_mut();
+            }
+            let ptr = self
+                .0
+                .memalign(align, size.saturating_add(self.0.log_padding()));
+            self.0.track(ptr);
+            ptr
         }
     }
 
@@ -380,7 +842,33 @@ impl<A: Allocator> Dlmalloc<A> {
         if ptr.is_null() {
             return self.c_malloc(new_size);
         }
-        self.0.realloc(ptr, new_size)
+        if !self.0.reserve_log() {
+            return ptr::null_mut();
+        }
+        // The log index is kept at the end of the chunk, which moves as it's
+        // resized, so read it first.
+        let index = if self.0.is_tracked(ptr) {
+            Some(self.0.log_index(ptr))
+        } else {
+            None
+        };
+        let tracked = index.is_some();
+        let new = self
+            .0
+            .realloc(ptr, new_size.saturating_add(self.0.log_padding()));
+        if new.is_null() {
+            return new;
+        }
+        // The chunk is tracked afresh if it was tracked before or if it moved
+        // while a checkpoint is active, in which case it counts as a new
+        // allocation.
+        if let Some(index) = index {
+            self.0.forget(index);
+        }
+        if tracked || new != ptr {
+            self.0.track(new);
+        }
+        new
     }
 
     /// Frees `ptr`.
@@ -398,6 +886,7 @@ impl<A: Allocator> Dlmalloc<A> {
         if ptr.is_null() {
             return;
         }
+        self.0.untrack(ptr);
         self.0.free(ptr)
     }
 }
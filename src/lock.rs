@@ -0,0 +1,164 @@
+#[cfg(target_has_atomic = "8")]
+use core::hint;
+#[cfg(target_has_atomic = "ptr")]
+use core::sync::atomic::AtomicUsize;
+#[cfg(target_has_atomic = "8")]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A raw mutual exclusion primitive used to guard a [`LockedDlmalloc`].
+///
+/// This mirrors the shape of `lock_api::RawMutex` without pulling in the
+/// dependency: a lock that can be created in a `const` context and that
+/// doesn't protect any data of its own.
+///
+/// The lock of an allocator is picked through its type, for example
+/// `LockedDlmalloc<System, TicketLock>`. This crate ships [`SpinLock`] and
+/// [`TicketLock`] everywhere, `PthreadMutex` on Unix, `FutexLock` on Linux and
+/// `SrwLock` on Windows and `WasmLock` on wasm with the `atomics` target
+/// feature. `GlobalDlmalloc` uses the platform's lock where there is one and
+/// [`SpinLock`] elsewhere, and `GlobalDlmallocWith` can be given any lock
+/// which implements `GlobalLock`.
+///
+/// # Safety
+///
+/// Implementations must guarantee that between a successful `lock` (or
+/// `try_lock` returning `true`) and the matching `unlock` no other call to
+/// `lock` or `try_lock` on the same instance succeeds. Implementations must
+/// also never allocate memory through the global allocator, as they may be
+/// used to implement it.
+///
+/// [`LockedDlmalloc`]: crate::LockedDlmalloc
+pub unsafe trait RawMutex {
+    /// An unlocked instance of this mutex.
+    const INIT: Self;
+
+    /// Acquires this mutex, blocking the current thread until it's able to
+    /// do so.
+    fn lock(&self);
+
+    /// Attempts to acquire this mutex without blocking, returning whether it
+    /// was acquired.
+    fn try_lock(&self) -> bool;
+
+    /// Releases this mutex.
+    ///
+    /// # Safety
+    ///
+    /// The mutex must be held by the current context.
+    unsafe fn unlock(&self);
+}
+
+/// A simple test-and-test-and-set spin lock.
+///
+/// This lock has no dependencies on the platform and is suitable for
+/// bare-metal targets or programs with little contention on the allocator.
+#[cfg(target_has_atomic = "8")]
+pub struct SpinLock {
+    locked: AtomicBool,
+}
+
+#[cfg(target_has_atomic = "8")]
+impl SpinLock {
+    /// Creates a new unlocked spin lock.
+    pub const fn new() -> SpinLock {
+        SpinLock {
+            locked: AtomicBool::new(false),
+        }
+    }
+}
+
+#[cfg(target_has_atomic = "8")]
+impl Default for SpinLock {
+    fn default() -> SpinLock {
+        SpinLock::new()
+    }
+}
+
+#[cfg(target_has_atomic = "8")]
+unsafe impl RawMutex for SpinLock {
+    const INIT: SpinLock = SpinLock::new();
+
+    fn lock(&self) {
+        while !self.try_lock() {
+            while self.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+/// A fair spin lock which hands out the lock in the order it was requested.
+///
+/// Like [`SpinLock`] this has no dependencies on the platform, but under
+/// contention it trades some throughput for not starving any waiter.
+///
+/// Waiters spin without ever yielding to the operating system, and only the
+/// one holding the next ticket can take the lock. When there are more
+/// runnable threads than cores that waiter may not be running when the lock
+/// is released, so every waiter behind it spins for whole time slices. This
+/// lock is meant for bare-metal targets or programs with no more threads
+/// using the allocator than cores; elsewhere prefer a lock which blocks,
+/// such as the platform's.
+#[cfg(target_has_atomic = "ptr")]
+pub struct TicketLock {
+    next: AtomicUsize,
+    serving: AtomicUsize,
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl TicketLock {
+    /// Creates a new unlocked ticket lock.
+    pub const fn new() -> TicketLock {
+        TicketLock {
+            next: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl Default for TicketLock {
+    fn default() -> TicketLock {
+        TicketLock::new()
+    }
+}
+
+#[cfg(target_has_atomic = "ptr")]
+unsafe impl RawMutex for TicketLock {
+    const INIT: TicketLock = TicketLock::new();
+
+    fn lock(&self) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            hint::spin_loop();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.serving
+            .store(serving.wrapping_add(1), Ordering::Release);
+    }
+}
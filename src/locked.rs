@@ -0,0 +1,135 @@
+use crate::{Allocator, Dlmalloc, RawMutex, Stats};
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+
+/// A `Dlmalloc` guarded by a lock of type `L`, usable from a `static`.
+///
+/// Unlike [`GlobalDlmalloc`](crate::GlobalDlmalloc), which is hardwired to the
+/// platform's system allocator and lock, this type works with any
+/// [`Allocator`] and any [`RawMutex`]. It can be constructed in a `const`
+/// context and implements `GlobalAlloc`, so it can be used as a
+/// `#[global_allocator]` on targets without an operating system:
+///
+/// ```ignore
+/// #[global_allocator]
+/// static A: LockedDlmalloc<MyRegion, SpinLock> =
+///     LockedDlmalloc::new(Dlmalloc::new_with_allocator(MyRegion::new()));
+/// ```
+pub struct LockedDlmalloc<A, L> {
+    lock: L,
+    heap: UnsafeCell<Dlmalloc<A>>,
+}
+
+unsafe impl<A: Send, L: RawMutex + Sync> Sync for LockedDlmalloc<A, L> {}
+
+impl<A, L: RawMutex> LockedDlmalloc<A, L> {
+    /// Wraps `heap` in a new, unlocked, lock.
+    pub const fn new(heap: Dlmalloc<A>) -> LockedDlmalloc<A, L> {
+        LockedDlmalloc {
+            lock: L::INIT,
+            heap: UnsafeCell::new(heap),
+        }
+    }
+
+    /// Consumes this lock, returning the underlying heap.
+    pub fn into_inner(self) -> Dlmalloc<A> {
+        self.heap.into_inner()
+    }
+
+    /// Returns a mutable reference to the underlying heap.
+    ///
+    /// No locking is needed since the `&mut` borrow guarantees exclusive
+    /// access.
+    pub fn get_mut(&mut self) -> &mut Dlmalloc<A> {
+        self.heap.get_mut()
+    }
+
+    /// Runs `f` with exclusive access to the underlying heap, for example to
+    /// change its configuration at runtime.
+    ///
+    /// The lock is held while `f` runs, so `f` must not allocate through this
+    /// allocator, including through the global allocator if this is the
+    /// `#[global_allocator]`.
+    pub fn configure<R>(&self, f: impl FnOnce(&mut Dlmalloc<A>) -> R) -> R {
+        f(self.lock().heap())
+    }
+
+    pub(crate) fn lock(&self) -> Guard<'_, A, L> {
+        self.lock.lock();
+        Guard { locked: self }
+    }
+
+    pub(crate) fn try_lock(&self) -> Option<Guard<'_, A, L>> {
+        if self.lock.try_lock() {
+            Some(Guard { locked: self })
+        } else {
+            None
+        }
+    }
+}
+
+impl<A: Allocator, L: RawMutex> LockedDlmalloc<A, L> {
+    /// Takes the lock and gives unused memory back to the system, see
+    /// [`Dlmalloc::trim`].
+    pub fn trim(&self, pad: usize) -> bool {
+        unsafe { self.lock().heap().trim(pad) }
+    }
+
+    /// Takes the lock and purges free memory, see [`Dlmalloc::purge`].
+    pub fn purge(&self, max_bytes: usize) -> usize {
+        self.lock().heap().purge(max_bytes)
+    }
+
+    /// Takes the lock and purges memory which has been free for a while,
+    /// see [`Dlmalloc::decay`].
+    pub fn decay(&self, now: u64) -> usize {
+        self.lock().heap().decay(now)
+    }
+
+    /// Takes the lock and returns statistics about the heap, see
+    /// [`Dlmalloc::stats`].
+    pub fn stats(&self) -> Stats {
+        self.lock().heap().stats()
+    }
+}
+
+pub(crate) struct Guard<'a, A, L: RawMutex> {
+    locked: &'a LockedDlmalloc<A, L>,
+}
+
+impl<A, L: RawMutex> Guard<'_, A, L> {
+    pub(crate) fn heap(&mut self) -> &mut Dlmalloc<A> {
+        // SAFETY: the lock is held for the lifetime of this guard.
+        unsafe { &mut *self.locked.heap.get() }
+    }
+}
+
+impl<A, L: RawMutex> Drop for Guard<'_, A, L> {
+    fn drop(&mut self) {
+        unsafe { self.locked.lock.unlock() }
+    }
+}
+
+unsafe impl<A: Allocator, L: RawMutex> GlobalAlloc for LockedDlmalloc<A, L> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().heap().malloc(layout.size(), layout.align())
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().heap().free(ptr, layout.size(), layout.align())
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.lock().heap().calloc(layout.size(), layout.align())
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.lock()
+            .heap()
+            .realloc(ptr, layout.size(), layout.align(), new_size)
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{Allocator, Dlmalloc, System};
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+/// A `Dlmalloc` which gives all of its memory back to its [`Allocator`] when
+/// dropped.
+///
+/// `Dlmalloc` itself doesn't implement `Drop`, as that would free memory
+/// still in use by anything that outlives it; releasing its memory requires
+/// an explicit call to the unsafe [`Dlmalloc::destroy`]. Wrapping a heap in
+/// this type instead promises that no allocation from it is used after it's
+/// dropped, so it can release its memory itself, including when a panic
+/// unwinds past it.
+///
+/// All of `Dlmalloc`'s methods are available through `Deref`. Every chunk
+/// this crate allocates, no matter how large, lives in a segment obtained
+/// from the `Allocator`, so dropping this frees everything the heap mapped
+/// except regions marked as externally owned.
+pub struct OwnedDlmalloc<A: Allocator = System>(ManuallyDrop<Dlmalloc<A>>);
+
+impl<A: Allocator> OwnedDlmalloc<A> {
+    /// Takes ownership of `heap`, releasing its memory when the returned
+    /// value is dropped.
+    ///
+    /// # Safety
+    ///
+    /// No allocation made from `heap`, before or after this call, may be used
+    /// once the returned value has been dropped.
+    pub const unsafe fn new(heap: Dlmalloc<A>) -> OwnedDlmalloc<A> {
+        OwnedDlmalloc(ManuallyDrop::new(heap))
+    }
+
+    /// Gives back the underlying heap without releasing its memory.
+    pub fn into_inner(self) -> Dlmalloc<A> {
+        let mut me = ManuallyDrop::new(self);
+        // SAFETY: `me` is never dropped, so the heap is only taken once.
+        unsafe { ManuallyDrop::take(&mut me.0) }
+    }
+}
+
+impl<A: Allocator> Deref for OwnedDlmalloc<A> {
+    type Target = Dlmalloc<A>;
+
+    fn deref(&self) -> &Dlmalloc<A> {
+        &self.0
+    }
+}
+
+impl<A: Allocator> DerefMut for OwnedDlmalloc<A> {
+    fn deref_mut(&mut self) -> &mut Dlmalloc<A> {
+        &mut self.0
+    }
+}
+
+impl<A: Allocator> Drop for OwnedDlmalloc<A> {
+    fn drop(&mut self) {
+        // SAFETY: the heap is never used again, and the contract of `new`
+        // guarantees none of its allocations are either.
+        unsafe {
+            let heap = ManuallyDrop::take(&mut self.0);
+            heap.destroy();
+        }
+    }
+}
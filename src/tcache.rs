@@ -0,0 +1,245 @@
+//! Per-thread caches of recently freed small chunks in front of
+//! `GlobalDlmalloc`.
+//!
+//! Each thread lazily gets a `Cache` with one singly-linked list of free
+//! chunks per small size class. Frees push onto the list for their class and
+//! allocations pop from it, neither of which needs the global lock. When a
+//! list grows past `MAX_CACHED` half of it is handed back to the shared heap
+//! in one go, and when a thread exits its whole cache is handed back by a TLS
+//! destructor.
+//!
+//! Cached chunks are still "in use" as far as the shared heap is concerned,
+//! so they show up in `GlobalDlmalloc::stats` as used memory and can't be
+//! trimmed until they're flushed.
+//!
+//! The caches are kept in `pthread` thread-specific data, so they're only
+//! available on Linux and macOS. Elsewhere `sys::cache` always returns `None`
+//! and every allocation goes to the shared heap as if the feature were off.
+
+use crate::dlmalloc::{CHUNK_OVERHEAD, MALLOC_ALIGNMENT, MIN_CHUNK_SIZE};
+use core::alloc::Layout;
+use core::ptr;
+
+/// Largest request size served from the cache.
+const MAX_CACHED_SIZE: usize = 1024;
+
+/// Maximum number of chunks kept in one size class before half of them are
+/// flushed back to the shared heap.
+const MAX_CACHED: usize = 32;
+
+const NBINS: usize = chunk_size(MAX_CACHED_SIZE) / MALLOC_ALIGNMENT + 1;
+
+/// Returns the size of the chunk dlmalloc would use for a request of `size`
+/// bytes, mirroring `request2size`.
+///
+/// Two requests with the same chunk size can be served by the same chunk and
+/// freed with either request's size, so this is what size classes are keyed
+/// on.
+const fn chunk_size(size: usize) -> usize {
+    let padded = (size + CHUNK_OVERHEAD + MALLOC_ALIGNMENT - 1) & !(MALLOC_ALIGNMENT - 1);
+    if padded < MIN_CHUNK_SIZE {
+        MIN_CHUNK_SIZE
+    } else {
+        padded
+    }
+}
+
+fn bin_index(layout: &Layout) -> Option<usize> {
+    if layout.size() > MAX_CACHED_SIZE || layout.align() > MALLOC_ALIGNMENT {
+        return None;
+    }
+    Some(chunk_size(layout.size()) / MALLOC_ALIGNMENT)
+}
+
+struct Bin {
+    head: *mut u8,
+    count: usize,
+}
+
+struct Cache {
+    bins: [Bin; NBINS],
+}
+
+impl Cache {
+    unsafe fn pop(&mut self, idx: usize) -> *mut u8 {
+        let bin = &mut self.bins[idx];
+        let ptr = bin.head;
+        if !ptr.is_null() {
+            bin.head = *ptr.cast::<*mut u8>();
+            bin.count -= 1;
+        }
+        ptr
+    }
+
+    unsafe fn push(&mut self, idx: usize, ptr: *mut u8) {
+        let bin = &mut self.bins[idx];
+        *ptr.cast::<*mut u8>() = bin.head;
+        bin.head = ptr;
+        bin.count += 1;
+        if bin.count > MAX_CACHED {
+            self.flush(idx, MAX_CACHED / 2);
+        }
+    }
+
+    /// Hands `n` chunks from the bin at `idx` back to the shared heap.
+    unsafe fn flush(&mut self, idx: usize, n: usize) {
+        let _guard = crate::global::lock();
+        let dlmalloc = ptr::addr_of_mut!(crate::global::DLMALLOC);
+        for _ in 0..n {
+            let ptr = self.pop(idx);
+            if ptr.is_null() {
+                break;
+            }
+            (*dlmalloc).c_free(ptr);
+        }
+    }
+
+    unsafe fn flush_all(&mut self) {
+        for idx in 0..NBINS {
+            let n = self.bins[idx].count;
+            if n > 0 {
+                self.flush(idx, n);
+            }
+        }
+    }
+}
+
+/// Attempts to serve `layout` from the current thread's cache, returning null
+/// if that isn't possible.
+pub unsafe fn alloc(layout: &Layout) -> *mut u8 {
+    match bin_index(layout) {
+        Some(idx) => match sys::cache() {
+            Some(cache) => (*cache).pop(idx),
+            None => ptr::null_mut(),
+        },
+        None => ptr::null_mut(),
+    }
+}
+
+/// Attempts to stash `ptr` in the current thread's cache, returning whether
+/// it was cached.
+pub unsafe fn dealloc(ptr: *mut u8, layout: &Layout) -> bool {
+    match bin_index(layout) {
+        Some(idx) => match sys::cache() {
+            Some(cache) => {
+                (*cache).push(idx, ptr);
+                true
+            }
+            None => false,
+        },
+        None => false,
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use super::{Bin, Cache, NBINS};
+    use core::mem;
+    use core::ptr;
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    const UNINIT: u8 = 0;
+    const READY: u8 = 1;
+    const FAILED: u8 = 2;
+
+    static STATE: AtomicU8 = AtomicU8::new(UNINIT);
+    static mut KEY: libc::pthread_key_t = 0;
+
+    /// Stored in the key once a thread's cache has been torn down, so frees
+    /// run by later TLS destructors go straight to the shared heap instead of
+    /// creating a new cache. Any address that can't be a `Cache` works.
+    fn dead() -> *mut libc::c_void {
+        ptr::addr_of!(STATE).cast_mut().cast()
+    }
+
+    /// Returns the current thread's cache, creating it if needed.
+    ///
+    /// Returns `None` if TLS isn't available or the thread is exiting.
+    pub unsafe fn cache() -> Option<*mut Cache> {
+        let key = key()?;
+        let cache = libc::pthread_getspecific(key);
+        if cache == dead() {
+            return None;
+        }
+        if !cache.is_null() {
+            return Some(cache.cast());
+        }
+        create(key)
+    }
+
+    unsafe fn key() -> Option<libc::pthread_key_t> {
+        match STATE.load(Ordering::Acquire) {
+            READY => return Some(KEY),
+            FAILED => return None,
+            _ => {}
+        }
+        let _guard = crate::global::lock();
+        if STATE.load(Ordering::Relaxed) == UNINIT {
+            let state = if libc::pthread_key_create(ptr::addr_of_mut!(KEY), Some(destroy)) == 0 {
+                READY
+            } else {
+                FAILED
+            };
+            STATE.store(state, Ordering::Release);
+        }
+        if STATE.load(Ordering::Relaxed) == READY {
+            Some(KEY)
+        } else {
+            None
+        }
+    }
+
+    #[cold]
+    unsafe fn create(key: libc::pthread_key_t) -> Option<*mut Cache> {
+        let cache = {
+            let _guard = crate::global::lock();
+            let dlmalloc = ptr::addr_of_mut!(crate::global::DLMALLOC);
+            (*dlmalloc)
+                .c_malloc(mem::size_of::<Cache>())
+                .cast::<Cache>()
+        };
+        if cache.is_null() {
+            return None;
+        }
+        cache.write(Cache {
+            bins: [const {
+                Bin {
+                    head: ptr::null_mut(),
+                    count: 0,
+                }
+            }; NBINS],
+        });
+        if libc::pthread_setspecific(key, cache.cast()) != 0 {
+            release(cache);
+            return None;
+        }
+        Some(cache)
+    }
+
+    unsafe fn release(cache: *mut Cache) {
+        (*cache).flush_all();
+        let _guard = crate::global::lock();
+        let dlmalloc = ptr::addr_of_mut!(crate::global::DLMALLOC);
+        (*dlmalloc).c_free(cache.cast());
+    }
+
+    unsafe extern "C" fn destroy(cache: *mut libc::c_void) {
+        if cache != dead() {
+            release(cache.cast());
+        }
+        // Setting a non-null value means we may get called again, with
+        // `dead()`, if another destructor frees memory; after the system's
+        // limit on destructor rounds the key is left as `dead()`.
+        libc::pthread_setspecific(KEY, dead());
+    }
+}
+
+// No per-thread caches on other platforms.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use super::Cache;
+
+    pub unsafe fn cache() -> Option<*mut Cache> {
+        None
+    }
+}
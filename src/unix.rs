@@ -1,19 +1,103 @@
-use crate::Allocator;
+use crate::{Allocator, RawMutex};
+use core::cell::{Cell, UnsafeCell};
+use core::cmp;
+#[cfg(target_os = "linux")]
+use core::ffi::CStr;
 use core::ptr;
+#[cfg(target_os = "linux")]
+use core::sync::atomic::AtomicU32;
+#[cfg(feature = "global")]
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 /// System setting for Linux
 pub struct System {
-    _priv: (),
+    // 0 to use the kernel's page size.
+    page_size: usize,
+    #[cfg(target_os = "linux")]
+    name: Option<&'static CStr>,
 }
 
 impl System {
+    /// Creates a new handle to the system's memory.
     pub const fn new() -> System {
-        System { _priv: () }
+        System {
+            page_size: 0,
+            #[cfg(target_os = "linux")]
+            name: None,
+        }
+    }
+
+    /// Labels every mapping made for the heap with `name`, so that it shows
+    /// up as `[anon:<name>]` in `/proc/<pid>/maps` and `/proc/<pid>/smaps`.
+    ///
+    /// This makes it possible to tell apart the memory of several heaps in
+    /// one process. The kernel limits names to 80 bytes, and only allows
+    /// printable characters other than `` \`$[] ``. Naming mappings needs
+    /// Linux 5.17 built with `CONFIG_ANON_VMA_NAME`; elsewhere mappings are
+    /// left unnamed.
+    ///
+    /// ```ignore
+    /// let heap = Dlmalloc::new_with_allocator(System::new().with_name(c"dlmalloc:plugin-a"));
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub const fn with_name(self, name: &'static CStr) -> System {
+        System {
+            name: Some(name),
+            ..self
+        }
+    }
+
+    /// Labels the `size` bytes at `ptr` with this allocator's name, if any.
+    #[cfg(target_os = "linux")]
+    fn name_mapping(&self, ptr: *mut libc::c_void, size: usize) {
+        if let Some(name) = self.name {
+            unsafe {
+                libc::prctl(
+                    libc::PR_SET_VMA,
+                    libc::PR_SET_VMA_ANON_NAME as libc::c_ulong,
+                    ptr as libc::c_ulong,
+                    size as libc::c_ulong,
+                    name.as_ptr() as libc::c_ulong,
+                );
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn name_mapping(&self, _ptr: *mut libc::c_void, _size: usize) {}
+
+    /// Reports `page_size` as the page size instead of the kernel's, which
+    /// is queried with `sysconf(_SC_PAGESIZE)` by default.
+    ///
+    /// The heap grows and shrinks by at least this much at a time, which can
+    /// be used to test behaviour with larger pages than the machine has. It
+    /// must be a power of two. A size smaller than the kernel's page size
+    /// can't be honored, so the kernel's is reported instead.
+    pub const fn with_page_size(self, page_size: usize) -> System {
+        assert!(page_size.is_power_of_two());
+        System { page_size, ..self }
     }
 }
 
-#[cfg(feature = "global")]
-static mut LOCK: libc::pthread_mutex_t = libc::PTHREAD_MUTEX_INITIALIZER;
+/// Returns the kernel's page size, which is only queried once.
+fn page_size() -> usize {
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+    let size = PAGE_SIZE.load(Ordering::Relaxed);
+    if size != 0 {
+        return size;
+    }
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    PAGE_SIZE.store(size, Ordering::Relaxed);
+    size
+}
+
+impl Default for System {
+    fn default() -> System {
+        System::new()
+    }
+}
 
 unsafe impl Allocator for System {
     fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
@@ -30,6 +114,7 @@ unsafe impl Allocator for System {
         if addr == libc::MAP_FAILED {
             (ptr::null_mut(), 0, 0)
         } else {
+            self.name_mapping(addr, size);
             (addr.cast(), size, 0)
         }
     }
@@ -50,7 +135,10 @@ unsafe impl Allocator for System {
         ptr::null_mut()
     }
 
+    // The region at `ptr` is one handed out by this allocator, which the
+    // `Allocator` contract guarantees, but clippy can't see that.
     #[cfg(target_os = "linux")]
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     fn free_part(&self, ptr: *mut u8, oldsize: usize, newsize: usize) -> bool {
         unsafe {
             let rc = libc::mremap(ptr.cast(), oldsize, newsize, 0);
@@ -61,7 +149,10 @@ unsafe impl Allocator for System {
         }
     }
 
+    // The region at `ptr` is one handed out by this allocator, which the
+    // `Allocator` contract guarantees, but clippy can't see that.
     #[cfg(target_os = "macos")]
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     fn free_part(&self, ptr: *mut u8, oldsize: usize, newsize: usize) -> bool {
         unsafe { libc::munmap(ptr.add(newsize).cast(), oldsize - newsize) == 0 }
     }
@@ -79,53 +170,712 @@ unsafe impl Allocator for System {
     }
 
     fn page_size(&self) -> usize {
-        4096
+        // Both are powers of two, so the larger one is a multiple of the
+        // kernel's page size.
+        cmp::max(self.page_size, page_size())
+    }
+
+    fn reserve(&self, size: usize) -> (*mut u8, usize, u32) {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_NONE,
+                libc::MAP_ANON | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            (ptr::null_mut(), 0, 0)
+        } else {
+            self.name_mapping(addr, size);
+            (addr.cast(), size, 0)
+        }
+    }
+
+    fn commit(&self, ptr: *mut u8, size: usize) -> bool {
+        unsafe { libc::mprotect(ptr.cast(), size, libc::PROT_WRITE | libc::PROT_READ) == 0 }
+    }
+
+    fn decommit(&self, ptr: *mut u8, size: usize) -> bool {
+        // Mapping fresh inaccessible pages over the range drops its contents,
+        // so it reads back as zeros once committed again.
+        let addr = unsafe {
+            libc::mmap(
+                ptr.cast(),
+                size,
+                libc::PROT_NONE,
+                libc::MAP_ANON | libc::MAP_PRIVATE | libc::MAP_NORESERVE | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return false;
+        }
+        // The fresh mapping also loses the name, so label it again.
+        self.name_mapping(addr, size);
+        true
+    }
+
+    // `MADV_DONTNEED` drops the pages right away, so the process's resident
+    // size goes down immediately.
+    #[cfg(target_os = "linux")]
+    fn purge(&self, ptr: *mut u8, size: usize) -> bool {
+        unsafe { libc::madvise(ptr.cast(), size, libc::MADV_DONTNEED) == 0 }
+    }
+
+    // `MADV_DONTNEED` doesn't free anything on macOS, only `MADV_FREE` does.
+    #[cfg(target_os = "macos")]
+    fn purge(&self, ptr: *mut u8, size: usize) -> bool {
+        unsafe { libc::madvise(ptr.cast(), size, libc::MADV_FREE) == 0 }
     }
 }
 
-#[cfg(feature = "global")]
-pub fn acquire_global_lock() {
-    unsafe { assert_eq!(libc::pthread_mutex_lock(ptr::addr_of_mut!(LOCK)), 0) }
+/// An [`Allocator`] for Linux which backs the heap with 2 MiB huge pages.
+///
+/// Memory is mapped aligned to 2 MiB and marked with
+/// `madvise(MADV_HUGEPAGE)` so that the kernel backs it with transparent
+/// huge pages where it can. [`HugePageSystem::hugetlb`] instead maps
+/// memory with `MAP_HUGETLB`, which only succeeds if huge pages have been
+/// set aside through `vm.nr_hugepages`, falling back to transparent huge
+/// pages when none are left.
+///
+/// Set the heap's granularity to [`HugePageSystem::PAGE_SIZE`] so that it
+/// grows and shrinks by whole huge pages; with a smaller granularity `trim`
+/// can only release memory in multiples of it:
+///
+/// ```ignore
+/// let mut heap = Dlmalloc::new_with_allocator(HugePageSystem::new());
+/// heap.set_granularity(HugePageSystem::PAGE_SIZE);
+/// ```
+#[cfg(target_os = "linux")]
+pub struct HugePageSystem {
+    hugetlb: bool,
 }
 
-#[cfg(feature = "global")]
-pub fn release_global_lock() {
-    unsafe { assert_eq!(libc::pthread_mutex_unlock(ptr::addr_of_mut!(LOCK)), 0) }
+#[cfg(target_os = "linux")]
+impl HugePageSystem {
+    /// The size of the huge pages used, and of the alignment of every
+    /// region this maps.
+    pub const PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+    /// Creates a new handle to the system's memory using transparent huge
+    /// pages.
+    pub const fn new() -> HugePageSystem {
+        HugePageSystem { hugetlb: false }
+    }
+
+    /// Creates a new handle to the system's memory which tries to use
+    /// `MAP_HUGETLB` pages first.
+    pub const fn hugetlb() -> HugePageSystem {
+        HugePageSystem { hugetlb: true }
+    }
+
+    /// Maps `size` bytes aligned to `PAGE_SIZE`, by over-allocating and
+    /// unmapping whatever lies outside the aligned range.
+    fn map_aligned(&self, size: usize, prot: libc::c_int, flags: libc::c_int) -> *mut u8 {
+        let len = size + Self::PAGE_SIZE;
+        unsafe {
+            let addr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_ANON | libc::MAP_PRIVATE | flags,
+                -1,
+                0,
+            );
+            if addr == libc::MAP_FAILED {
+                return ptr::null_mut();
+            }
+            let addr = addr.cast::<u8>();
+            let head = addr.align_offset(Self::PAGE_SIZE);
+            if head > 0 {
+                libc::munmap(addr.cast(), head);
+            }
+            let tail = len - head - size;
+            if tail > 0 {
+                libc::munmap(addr.add(head + size).cast(), tail);
+            }
+            let addr = addr.add(head);
+            libc::madvise(addr.cast(), size, libc::MADV_HUGEPAGE);
+            addr
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Default for HugePageSystem {
+    fn default() -> HugePageSystem {
+        HugePageSystem::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe impl Allocator for HugePageSystem {
+    fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
+        let size = match size.checked_next_multiple_of(Self::PAGE_SIZE) {
+            Some(size) => size,
+            None => return (ptr::null_mut(), 0, 0),
+        };
+        if self.hugetlb {
+            let addr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    size,
+                    libc::PROT_WRITE | libc::PROT_READ,
+                    libc::MAP_ANON | libc::MAP_PRIVATE | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
+                    -1,
+                    0,
+                )
+            };
+            if addr != libc::MAP_FAILED {
+                return (addr.cast(), size, 0);
+            }
+        }
+        let addr = self.map_aligned(size, libc::PROT_WRITE | libc::PROT_READ, 0);
+        if addr.is_null() {
+            (ptr::null_mut(), 0, 0)
+        } else {
+            (addr, size, 0)
+        }
+    }
+
+    fn remap(&self, _ptr: *mut u8, _oldsize: usize, _newsize: usize, _can_move: bool) -> *mut u8 {
+        ptr::null_mut()
+    }
+
+    fn free_part(&self, ptr: *mut u8, oldsize: usize, newsize: usize) -> bool {
+        // Huge pages can't be partially unmapped, and splitting a
+        // transparent one would defeat the point.
+        if newsize % Self::PAGE_SIZE != 0 {
+            return false;
+        }
+        unsafe { libc::munmap(ptr.wrapping_add(newsize).cast(), oldsize - newsize) == 0 }
+    }
+
+    fn free(&self, ptr: *mut u8, size: usize) -> bool {
+        unsafe { libc::munmap(ptr.cast(), size) == 0 }
+    }
+
+    fn can_release_part(&self, _flags: u32) -> bool {
+        true
+    }
+
+    fn allocates_zeros(&self) -> bool {
+        true
+    }
+
+    fn page_size(&self) -> usize {
+        Self::PAGE_SIZE
+    }
+
+    fn reserve(&self, size: usize) -> (*mut u8, usize, u32) {
+        // `MAP_HUGETLB` pages are set aside as soon as they're mapped, so
+        // there's no reserving them without committing them too.
+        if self.hugetlb {
+            return (ptr::null_mut(), 0, 0);
+        }
+        let size = match size.checked_next_multiple_of(Self::PAGE_SIZE) {
+            Some(size) => size,
+            None => return (ptr::null_mut(), 0, 0),
+        };
+        let addr = self.map_aligned(size, libc::PROT_NONE, libc::MAP_NORESERVE);
+        if addr.is_null() {
+            (ptr::null_mut(), 0, 0)
+        } else {
+            (addr, size, 0)
+        }
+    }
+
+    fn commit(&self, ptr: *mut u8, size: usize) -> bool {
+        unsafe { libc::mprotect(ptr.cast(), size, libc::PROT_WRITE | libc::PROT_READ) == 0 }
+    }
+
+    fn decommit(&self, ptr: *mut u8, size: usize) -> bool {
+        if (ptr as usize | size) % Self::PAGE_SIZE != 0 {
+            return false;
+        }
+        unsafe {
+            let addr = libc::mmap(
+                ptr.cast(),
+                size,
+                libc::PROT_NONE,
+                libc::MAP_ANON | libc::MAP_PRIVATE | libc::MAP_NORESERVE | libc::MAP_FIXED,
+                -1,
+                0,
+            );
+            if addr == libc::MAP_FAILED {
+                return false;
+            }
+            // The fresh mapping doesn't inherit the advice.
+            libc::madvise(addr, size, libc::MADV_HUGEPAGE);
+            true
+        }
+    }
+
+    fn purge(&self, ptr: *mut u8, size: usize) -> bool {
+        unsafe { libc::madvise(ptr.cast(), size, libc::MADV_DONTNEED) == 0 }
+    }
+}
+
+/// An [`Allocator`] which maps memory from a file shared with anything else
+/// mapping it, such as a `memfd_create(2)` file passed to a child process or
+/// a file on disk.
+///
+/// Address space for the whole file, up to a maximum length, is reserved
+/// up front. The heap grows by extending the file with `ftruncate(2)` and
+/// mapping the new part with `MAP_SHARED` right after the previous one, so
+/// the heap is one contiguous range whose layout matches the file's:
+/// [`FileBacked::offset_of`] turns a pointer into the file offset another
+/// process mapping the file can find it at. A child created with `fork(2)`
+/// sees the memory at the same addresses.
+///
+/// Memory the heap gives back is unmapped, and the file shrinks again if
+/// it was at the end. Otherwise, on Linux, a hole is punched in the file so
+/// it stops taking up space, but the range isn't reused: the heap only ever
+/// grows at the end of the file, so a heap which frees and allocates large
+/// regions in turn can run out of `max_len` even though most of the file
+/// is holes.
+///
+/// Only the memory handed out lives in the file. The heap's bookkeeping
+/// lives in the `Dlmalloc` itself, so other processes can read and write
+/// allocations through their own mapping of the file but can't allocate
+/// from the same heap.
+pub struct FileBacked {
+    fd: libc::c_int,
+    base: *mut u8,
+    max_len: usize,
+    // Current length of the file, all of which is mapped at `base`.
+    len: Cell<usize>,
+}
+
+unsafe impl Send for FileBacked {}
+
+impl FileBacked {
+    /// Creates an allocator backed by a new anonymous file from
+    /// `memfd_create(2)`, named `name` for debugging purposes, which can
+    /// grow up to `max_len` bytes.
+    ///
+    /// The file is created with `MFD_CLOEXEC`. Returns `None` if it can't be
+    /// created.
+    #[cfg(target_os = "linux")]
+    pub fn memfd(name: &CStr, max_len: usize) -> Option<FileBacked> {
+        unsafe {
+            let fd = libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC);
+            if fd < 0 {
+                return None;
+            }
+            FileBacked::from_fd(fd, max_len)
+        }
+    }
+
+    /// Creates an allocator backed by the file open as `fd`, which can grow
+    /// up to `max_len` bytes.
+    ///
+    /// Anything already in the file is mapped too, at the start of the
+    /// range, and the heap's memory is appended after it. Returns `None`,
+    /// after closing `fd`, if the file is already longer than `max_len` or
+    /// the address space can't be reserved.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be a file descriptor open for reading and writing which
+    /// supports `mmap(2)`. The returned allocator takes ownership of it and
+    /// closes it when dropped. Nothing else may change the file's length
+    /// while the allocator is in use.
+    pub unsafe fn from_fd(fd: libc::c_int, max_len: usize) -> Option<FileBacked> {
+        let page = page_size();
+        let mut stat = core::mem::MaybeUninit::<libc::stat>::uninit();
+        if libc::fstat(fd, stat.as_mut_ptr()) != 0 {
+            libc::close(fd);
+            return None;
+        }
+        let len = stat.assume_init().st_size as usize;
+        let max_len = max_len.checked_next_multiple_of(page);
+        let (max_len, base) = match max_len {
+            Some(max_len) if len <= max_len => {
+                let base = libc::mmap(
+                    ptr::null_mut(),
+                    max_len,
+                    libc::PROT_NONE,
+                    libc::MAP_ANON | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+                    -1,
+                    0,
+                );
+                (max_len, base)
+            }
+            _ => (0, libc::MAP_FAILED),
+        };
+        if base == libc::MAP_FAILED {
+            libc::close(fd);
+            return None;
+        }
+        // Round the file up to a whole number of pages so the heap's memory
+        // starts on a page boundary.
+        let aligned = len.next_multiple_of(page);
+        if aligned > max_len
+            || (aligned > len && libc::ftruncate(fd, aligned as libc::off_t) != 0)
+            || (aligned > 0 && !map_shared(fd, base.cast(), 0, aligned))
+        {
+            libc::munmap(base, max_len);
+            libc::close(fd);
+            return None;
+        }
+        Some(FileBacked {
+            fd,
+            base: base.cast(),
+            max_len,
+            len: Cell::new(aligned),
+        })
+    }
+
+    /// Returns the file descriptor of the backing file.
+    pub fn fd(&self) -> libc::c_int {
+        self.fd
+    }
+
+    /// Returns the address the start of the file is mapped at.
+    pub fn base(&self) -> *mut u8 {
+        self.base
+    }
+
+    /// Returns the offset in the backing file of the memory at `ptr`, or
+    /// `None` if `ptr` doesn't point into the file.
+    pub fn offset_of(&self, ptr: *const u8) -> Option<usize> {
+        let offset = (ptr as usize).checked_sub(self.base as usize)?;
+        if offset < self.len.get() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Replaces the `size` bytes at `ptr` with inaccessible anonymous memory,
+    /// keeping them reserved.
+    fn unmap(&self, ptr: *mut u8, size: usize) -> bool {
+        let addr = unsafe {
+            libc::mmap(
+                ptr.cast(),
+                size,
+                libc::PROT_NONE,
+                libc::MAP_ANON | libc::MAP_PRIVATE | libc::MAP_NORESERVE | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return false;
+        }
+        // Give the space back to the file system too, by shrinking the file
+        // if this was its end and by punching a hole in it otherwise.
+        let offset = ptr as usize - self.base as usize;
+        if offset + size == self.len.get() {
+            if unsafe { libc::ftruncate(self.fd, offset as libc::off_t) } == 0 {
+                self.len.set(offset);
+            }
+        } else {
+            self.punch_hole(offset, size);
+        }
+        true
+    }
+
+    #[cfg(target_os = "linux")]
+    fn punch_hole(&self, offset: usize, size: usize) {
+        unsafe {
+            libc::fallocate(
+                self.fd,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                size as libc::off_t,
+            );
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn punch_hole(&self, _offset: usize, _size: usize) {}
+}
+
+/// Maps `size` bytes of `fd` at `offset` over the reserved memory at `addr`.
+unsafe fn map_shared(fd: libc::c_int, addr: *mut u8, offset: usize, size: usize) -> bool {
+    let ret = libc::mmap(
+        addr.cast(),
+        size,
+        libc::PROT_WRITE | libc::PROT_READ,
+        libc::MAP_SHARED | libc::MAP_FIXED,
+        fd,
+        offset as libc::off_t,
+    );
+    ret != libc::MAP_FAILED
+}
+
+impl Drop for FileBacked {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base.cast(), self.max_len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+unsafe impl Allocator for FileBacked {
+    fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
+        let offset = self.len.get();
+        if size > self.max_len - offset {
+            return (ptr::null_mut(), 0, 0);
+        }
+        let addr = self.base.wrapping_add(offset);
+        unsafe {
+            if libc::ftruncate(self.fd, (offset + size) as libc::off_t) != 0 {
+                return (ptr::null_mut(), 0, 0);
+            }
+            if !map_shared(self.fd, addr, offset, size) {
+                libc::ftruncate(self.fd, offset as libc::off_t);
+                return (ptr::null_mut(), 0, 0);
+            }
+        }
+        self.len.set(offset + size);
+        (addr, size, 0)
+    }
+
+    fn remap(&self, _ptr: *mut u8, _oldsize: usize, _newsize: usize, _can_move: bool) -> *mut u8 {
+        ptr::null_mut()
+    }
+
+    fn free_part(&self, ptr: *mut u8, oldsize: usize, newsize: usize) -> bool {
+        self.unmap(ptr.wrapping_add(newsize), oldsize - newsize)
+    }
+
+    fn free(&self, ptr: *mut u8, size: usize) -> bool {
+        self.unmap(ptr, size)
+    }
+
+    fn can_release_part(&self, _flags: u32) -> bool {
+        true
+    }
+
+    fn allocates_zeros(&self) -> bool {
+        true
+    }
+
+    fn page_size(&self) -> usize {
+        page_size()
+    }
 }
 
+/// The lock used by `GlobalDlmalloc` on this platform.
 #[cfg(feature = "global")]
-/// allows the allocator to remain unsable in the child process,
-/// after a call to `fork(2)`
+pub type DefaultLock = PthreadMutex;
+
+/// A lock backed by a `pthread_mutex_t`.
+///
+/// Like the underlying mutex this must not be moved once it has been used,
+/// which in practice means it should live in a `static`.
+pub struct PthreadMutex {
+    inner: UnsafeCell<libc::pthread_mutex_t>,
+}
+
+unsafe impl Send for PthreadMutex {}
+unsafe impl Sync for PthreadMutex {}
+
+impl PthreadMutex {
+    /// Creates a new unlocked mutex.
+    pub const fn new() -> PthreadMutex {
+        PthreadMutex {
+            inner: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+        }
+    }
+
+    /// Resets the mutex to its initial unlocked state, regardless of who
+    /// holds it.
+    ///
+    /// Only valid in a freshly forked child, where the holder is gone and no
+    /// other thread can be using the mutex.
+    #[cfg(feature = "global")]
+    pub(crate) unsafe fn reinit(&self) {
+        self.inner.get().write(libc::PTHREAD_MUTEX_INITIALIZER);
+    }
+}
+
+impl Default for PthreadMutex {
+    fn default() -> PthreadMutex {
+        PthreadMutex::new()
+    }
+}
+
+unsafe impl RawMutex for PthreadMutex {
+    const INIT: PthreadMutex = PthreadMutex::new();
+
+    fn lock(&self) {
+        unsafe { assert_eq!(libc::pthread_mutex_lock(self.inner.get()), 0) }
+    }
+
+    fn try_lock(&self) -> bool {
+        unsafe { libc::pthread_mutex_trylock(self.inner.get()) == 0 }
+    }
+
+    unsafe fn unlock(&self) {
+        assert_eq!(libc::pthread_mutex_unlock(self.inner.get()), 0)
+    }
+}
+
+/// A lock built directly on Linux futexes.
+///
+/// Uncontended locking and unlocking is a single atomic operation, and
+/// waiters spin briefly before sleeping in the kernel.
+#[cfg(target_os = "linux")]
+pub struct FutexLock {
+    // 0: unlocked, 1: locked, 2: locked and there may be waiters
+    state: AtomicU32,
+}
+
+#[cfg(target_os = "linux")]
+impl FutexLock {
+    /// Creates a new unlocked lock.
+    pub const fn new() -> FutexLock {
+        FutexLock {
+            state: AtomicU32::new(0),
+        }
+    }
+
+    #[cold]
+    fn lock_contended(&self) {
+        for _ in 0..100 {
+            if self.state.load(Ordering::Relaxed) == 0 && self.try_lock() {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+        while self.state.swap(2, Ordering::Acquire) != 0 {
+            unsafe {
+                libc::syscall(
+                    libc::SYS_futex,
+                    self.state.as_ptr(),
+                    libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                    2,
+                    ptr::null::<libc::timespec>(),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Default for FutexLock {
+    fn default() -> FutexLock {
+        FutexLock::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe impl RawMutex for FutexLock {
+    const INIT: FutexLock = FutexLock::new();
+
+    fn lock(&self) {
+        if !self.try_lock() {
+            self.lock_contended();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.state
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        if self.state.swap(0, Ordering::Release) == 2 {
+            libc::syscall(
+                libc::SYS_futex,
+                self.state.as_ptr(),
+                libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+                1,
+            );
+        }
+    }
+}
+
+/// Registers `pthread_atfork` handlers keeping the global allocator usable
+/// in the child process after a call to `fork(2)`.
 ///
-/// #Safety
+/// This happens automatically the first time the global allocator is used,
+/// so calling this is no longer necessary; it's kept so existing callers
+/// continue to work.
 ///
-/// if used, this function must be called,
-/// before any allocations are made with the global allocator.
+/// # Safety
+///
+/// This function is safe to call at any time, it's only `unsafe` for
+/// compatibility.
+#[cfg(feature = "global")]
 pub unsafe fn enable_alloc_after_fork() {
-    // atfork must only be called once, to avoid a deadlock,
-    // where the handler attempts to acquire the global lock twice
-    static mut FORK_PROTECTED: bool = false;
+    register_fork_handlers()
+}
+
+/// Registers the `pthread_atfork` handlers for the global lock, once.
+///
+/// The handlers take the lock before forking so no other thread can be
+/// halfway through modifying the heap when the child is created, release it
+/// again in the parent, and reinitialize it in the child, where the thread
+/// that took it no longer exists.
+///
+/// Every call returns only once the handlers are registered, even if another
+/// thread is the one registering them.
+#[cfg(feature = "global")]
+pub fn register_fork_handlers() {
+    const UNREGISTERED: u8 = 0;
+    const REGISTERING: u8 = 1;
+    const REGISTERED: u8 = 2;
 
-    unsafe extern "C" fn _acquire_global_lock() {
-        acquire_global_lock()
+    static STATE: AtomicU8 = AtomicU8::new(UNREGISTERED);
+
+    unsafe extern "C" fn prepare() {
+        crate::global::acquire_global_lock()
     }
 
-    unsafe extern "C" fn _release_global_lock() {
-        release_global_lock()
+    unsafe extern "C" fn parent() {
+        crate::global::release_global_lock()
+    }
+
+    unsafe extern "C" fn child() {
+        crate::global::reinit_global_lock()
+    }
+
+    if STATE.load(Ordering::Acquire) == REGISTERED {
+        return;
+    }
+    match STATE.compare_exchange(
+        UNREGISTERED,
+        REGISTERING,
+        Ordering::Acquire,
+        Ordering::Acquire,
+    ) {
+        Ok(_) => {
+            unsafe {
+                libc::pthread_atfork(Some(prepare), Some(parent), Some(child));
+            }
+            STATE.store(REGISTERED, Ordering::Release);
+        }
+        Err(_) => {
+            while STATE.load(Ordering::Acquire) != REGISTERED {
+                core::hint::spin_loop();
+            }
+        }
     }
+}
 
-    acquire_global_lock();
-    // if a process forks,
-    // it will acquire the lock before any other thread,
-    // protecting it from deadlock,
-    // due to the child being created with only the calling thread.
-    if !FORK_PROTECTED {
-        libc::pthread_atfork(
-            Some(_acquire_global_lock),
-            Some(_release_global_lock),
-            Some(_release_global_lock),
-        );
-        FORK_PROTECTED = true;
+/// Returns an identifier for the current thread, never 0.
+#[cfg(feature = "global")]
+pub fn thread_id() -> usize {
+    unsafe { libc::pthread_self() as usize }
+}
+
+/// Prints `msg` to stderr and aborts the process, without allocating.
+#[cfg(feature = "global")]
+pub fn abort(msg: &str) -> ! {
+    unsafe {
+        libc::write(2, msg.as_ptr().cast(), msg.len());
+        libc::write(2, "\n".as_ptr().cast(), 1);
+        libc::abort()
     }
-    release_global_lock();
 }
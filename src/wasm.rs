@@ -1,9 +1,13 @@
 use crate::Allocator;
+#[cfg(target_feature = "atomics")]
+use crate::RawMutex;
 #[cfg(target_arch = "wasm32")]
 use core::arch::wasm32 as wasm;
 #[cfg(target_arch = "wasm64")]
 use core::arch::wasm64 as wasm;
 use core::ptr;
+#[cfg(target_feature = "atomics")]
+use core::sync::atomic::AtomicU32;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 #[cfg(target_os = "unknown")]
@@ -49,6 +53,10 @@ fn try_donate_preexisting(
 
 fn alloc_via_grow(size: usize, page_size: usize) -> (*mut u8, usize, u32) {
     let pages = size.div_ceil(page_size);
+    // The threads proposal specifies `memory.grow` on a shared memory as a
+    // single atomic step which returns the old size, so even with threads
+    // sharing this memory each caller that succeeds gets its own fresh range
+    // of pages, see the `grown_ranges_are_disjoint` test.
     let prev = wasm::memory_grow(0, pages);
 
     if prev == usize::max_value() {
@@ -79,11 +87,18 @@ pub struct System {
 }
 
 impl System {
+    /// Creates a new handle to the system's memory.
     pub const fn new() -> System {
         System { _priv: () }
     }
 }
 
+impl Default for System {
+    fn default() -> System {
+        System::new()
+    }
+}
+
 unsafe impl Allocator for System {
     fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
         let page_size = self.page_size();
@@ -125,7 +140,7 @@ unsafe impl Allocator for System {
 
 #[cfg(test)]
 mod tests {
-    use super::try_donate_preexisting;
+    use super::{alloc_via_grow, try_donate_preexisting};
     use core::sync::atomic::{AtomicBool, Ordering};
 
     fn legacy_grow_only(
@@ -166,6 +181,16 @@ mod tests {
         assert_eq!(try_donate_preexisting(&state, second), None);
     }
 
+    #[test]
+    fn grown_ranges_are_disjoint() {
+        let page_size = 64 * 1024;
+        let (first, first_len, _) = alloc_via_grow(1, page_size);
+        let (second, second_len, _) = alloc_via_grow(page_size + 1, page_size);
+        assert!(!first.is_null() && !second.is_null());
+        assert_eq!((first_len, second_len), (page_size, 2 * page_size));
+        assert!(second as usize >= first as usize + first_len);
+    }
+
     #[test]
     fn one_chunk_donates_only_once() {
         let state = AtomicBool::new(false);
@@ -182,21 +207,113 @@ mod tests {
     }
 }
 
-#[cfg(feature = "global")]
-pub fn acquire_global_lock() {
-    // single threaded, no need!
-    assert!(!cfg!(target_feature = "atomics"));
+/// The lock used by `GlobalDlmalloc` on this platform.
+#[cfg(all(feature = "global", target_feature = "atomics"))]
+pub type DefaultLock = WasmLock;
+
+/// The lock used by `GlobalDlmalloc` on this platform.
+#[cfg(all(feature = "global", not(target_feature = "atomics")))]
+pub type DefaultLock = crate::SpinLock;
+
+/// A lock for threaded wasm built on `memory.atomic.wait32` and
+/// `memory.atomic.notify`.
+///
+/// Waiters spin for a while before blocking. Note that browsers don't allow
+/// the main thread to block, so if the main thread may contend on this lock
+/// use a [`SpinLock`](crate::SpinLock) instead.
+#[cfg(target_feature = "atomics")]
+pub struct WasmLock {
+    // 0: unlocked, 1: locked, 2: locked and there may be waiters
+    state: AtomicU32,
 }
 
-#[cfg(feature = "global")]
-pub fn release_global_lock() {
-    // single threaded, no need!
-    assert!(!cfg!(target_feature = "atomics"));
+#[cfg(target_feature = "atomics")]
+impl WasmLock {
+    /// Creates a new unlocked lock.
+    pub const fn new() -> WasmLock {
+        WasmLock {
+            state: AtomicU32::new(0),
+        }
+    }
+
+    #[cold]
+    fn lock_contended(&self) {
+        for _ in 0..1000 {
+            if self.state.load(Ordering::Relaxed) == 0 && self.try_lock() {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+        while self.state.swap(2, Ordering::Acquire) != 0 {
+            unsafe {
+                wasm::memory_atomic_wait32(self.state.as_ptr().cast::<i32>(), 2, -1);
+            }
+        }
+    }
+}
+
+#[cfg(target_feature = "atomics")]
+impl Default for WasmLock {
+    fn default() -> WasmLock {
+        WasmLock::new()
+    }
+}
+
+#[cfg(target_feature = "atomics")]
+unsafe impl RawMutex for WasmLock {
+    const INIT: WasmLock = WasmLock::new();
+
+    fn lock(&self) {
+        if !self.try_lock() {
+            self.lock_contended();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.state
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        if self.state.swap(0, Ordering::Release) == 2 {
+            wasm::memory_atomic_notify(self.state.as_ptr().cast::<i32>(), 1);
+        }
+    }
 }
 
 #[allow(missing_docs)]
 #[cfg(feature = "global")]
 pub unsafe fn enable_alloc_after_fork() {
-    // single threaded, no need!
-    assert!(!cfg!(target_feature = "atomics"));
+    // no `fork()` on wasm
 }
+
+/// Returns an identifier for the current thread, or 0 if it isn't known.
+///
+/// Every thread has its own copy of thread-local statics, so the address of
+/// one tells threads apart. Without the `atomics` target feature there's
+/// only ever one thread.
+#[cfg(all(feature = "global", target_feature = "atomics"))]
+pub fn thread_id() -> usize {
+    #[thread_local]
+    static ID: u8 = 0;
+
+    ptr::addr_of!(ID) as usize
+}
+
+/// Returns an identifier for the current thread, or 0 if it isn't known.
+///
+/// Without the `atomics` target feature there's only ever one thread.
+#[cfg(all(feature = "global", not(target_feature = "atomics")))]
+pub fn thread_id() -> usize {
+    1
+}
+
+/// Aborts execution by trapping.
+#[cfg(feature = "global")]
+pub fn abort(_msg: &str) -> ! {
+    wasm::unreachable()
+}
+
+#[cfg(feature = "global")]
+pub fn register_fork_handlers() {}
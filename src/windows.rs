@@ -1,21 +1,29 @@
-use crate::Allocator;
+use crate::{Allocator, RawMutex};
+use core::cell::UnsafeCell;
 use core::mem::MaybeUninit;
 use core::ptr;
 use windows_sys::Win32::System::Memory::*;
 use windows_sys::Win32::System::SystemInformation::*;
-#[cfg(feature = "global")]
 use windows_sys::Win32::System::Threading::*;
 
+/// System setting for Windows
 pub struct System {
     _priv: (),
 }
 
 impl System {
+    /// Creates a new handle to the system's memory.
     pub const fn new() -> System {
         System { _priv: () }
     }
 }
 
+impl Default for System {
+    fn default() -> System {
+        System::new()
+    }
+}
+
 unsafe impl Allocator for System {
     fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
         let addr = unsafe {
@@ -38,6 +46,9 @@ unsafe impl Allocator for System {
         ptr::null_mut()
     }
 
+    // The region at `ptr` is one handed out by this allocator, which the
+    // `Allocator` contract guarantees, but clippy can't see that.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     fn free_part(&self, ptr: *mut u8, oldsize: usize, newsize: usize) -> bool {
         unsafe { VirtualFree(ptr.add(newsize).cast(), oldsize - newsize, MEM_DECOMMIT) != 0 }
     }
@@ -61,28 +72,96 @@ unsafe impl Allocator for System {
             info.assume_init_ref().dwPageSize as usize
         }
     }
+
+    fn reserve(&self, size: usize) -> (*mut u8, usize, u32) {
+        let addr = unsafe { VirtualAlloc(ptr::null_mut(), size, MEM_RESERVE, PAGE_NOACCESS) };
+
+        if addr.is_null() {
+            (ptr::null_mut(), 0, 0)
+        } else {
+            (addr.cast(), size, 0)
+        }
+    }
+
+    fn commit(&self, ptr: *mut u8, size: usize) -> bool {
+        unsafe { !VirtualAlloc(ptr.cast(), size, MEM_COMMIT, PAGE_READWRITE).is_null() }
+    }
+
+    fn decommit(&self, ptr: *mut u8, size: usize) -> bool {
+        unsafe { VirtualFree(ptr.cast(), size, MEM_DECOMMIT) != 0 }
+    }
 }
 
-// NB: `SRWLOCK_INIT` doesn't appear to be in `windows-sys`
+/// The lock used by `GlobalDlmalloc` on this platform.
 #[cfg(feature = "global")]
-static mut LOCK: SRWLOCK = SRWLOCK {
-    Ptr: ptr::null_mut(),
-};
+pub type DefaultLock = SrwLock;
 
-#[cfg(feature = "global")]
-pub fn acquire_global_lock() {
-    unsafe {
-        AcquireSRWLockExclusive(ptr::addr_of_mut!(LOCK));
+/// A lock backed by a Windows slim reader/writer lock, only ever taken
+/// exclusively.
+pub struct SrwLock {
+    inner: UnsafeCell<SRWLOCK>,
+}
+
+unsafe impl Send for SrwLock {}
+unsafe impl Sync for SrwLock {}
+
+impl SrwLock {
+    /// Creates a new unlocked lock.
+    pub const fn new() -> SrwLock {
+        // NB: `SRWLOCK_INIT` doesn't appear to be in `windows-sys`
+        SrwLock {
+            inner: UnsafeCell::new(SRWLOCK {
+                Ptr: ptr::null_mut(),
+            }),
+        }
     }
 }
 
-#[cfg(feature = "global")]
-pub fn release_global_lock() {
-    unsafe {
-        ReleaseSRWLockExclusive(ptr::addr_of_mut!(LOCK));
+impl Default for SrwLock {
+    fn default() -> SrwLock {
+        SrwLock::new()
+    }
+}
+
+unsafe impl RawMutex for SrwLock {
+    const INIT: SrwLock = SrwLock::new();
+
+    fn lock(&self) {
+        unsafe { AcquireSRWLockExclusive(self.inner.get()) }
+    }
+
+    fn try_lock(&self) -> bool {
+        unsafe { TryAcquireSRWLockExclusive(self.inner.get()) != 0 }
+    }
+
+    unsafe fn unlock(&self) {
+        ReleaseSRWLockExclusive(self.inner.get())
     }
 }
 
 /// Not needed on Windows
 #[cfg(feature = "global")]
 pub unsafe fn enable_alloc_after_fork() {}
+
+/// Returns an identifier for the current thread, never 0.
+#[cfg(feature = "global")]
+pub fn thread_id() -> usize {
+    unsafe { GetCurrentThreadId() as usize }
+}
+
+/// Aborts the process, without allocating.
+///
+/// There's no console to write `msg` to without pulling in more of
+/// `windows-sys`, so it's only visible in a debugger.
+#[cfg(feature = "global")]
+pub fn abort(_msg: &str) -> ! {
+    unsafe {
+        TerminateProcess(GetCurrentProcess(), 3);
+    }
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(feature = "global")]
+pub fn register_fork_handlers() {}
@@ -1,16 +1,24 @@
 use crate::Allocator;
 use core::ptr;
 
+/// System setting for Xous
 pub struct System {
     _priv: (),
 }
 
 impl System {
+    /// Creates a new handle to the system's memory.
     pub const fn new() -> System {
         System { _priv: () }
     }
 }
 
+impl Default for System {
+    fn default() -> System {
+        System::new()
+    }
+}
+
 #[cfg(target_arch = "riscv32")]
 mod sys {
     use core::arch::asm;
@@ -99,19 +107,32 @@ unsafe impl Allocator for System {
     }
 }
 
+/// The lock used by `GlobalDlmalloc` on this platform.
 #[cfg(feature = "global")]
-pub fn acquire_global_lock() {
-    // global feature should not be enabled
-    unimplemented!()
+pub type DefaultLock = crate::SpinLock;
+
+#[cfg(feature = "global")]
+pub unsafe fn enable_alloc_after_fork() {
+    // platform does not support `fork()` call
 }
 
+/// Returns an identifier for the current thread, or 0 if it isn't known.
 #[cfg(feature = "global")]
-pub fn release_global_lock() {
-    // global feature should not be enabled
-    unimplemented!()
+pub fn thread_id() -> usize {
+    0
 }
 
+/// Stops the current thread for good, without allocating.
+///
+/// Panicking could allocate, which re-enters the global allocator while its
+/// lock is held, and there's nothing portable to report `msg` with or to
+/// end the program, so this just spins.
 #[cfg(feature = "global")]
-pub unsafe fn enable_alloc_after_fork() {
-    // platform does not support `fork()` call
+pub fn abort(_msg: &str) -> ! {
+    loop {
+        core::hint::spin_loop();
+    }
 }
+
+#[cfg(feature = "global")]
+pub fn register_fork_handlers() {}
@@ -0,0 +1,93 @@
+use dlmalloc::{ArenaDlmalloc, Dlmalloc, LockedDlmalloc, SpinLock, System};
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::mpsc;
+use std::thread;
+
+#[global_allocator]
+static A: ArenaDlmalloc<System, SpinLock, 4> =
+    ArenaDlmalloc::new([const { LockedDlmalloc::new(Dlmalloc::new()) }; 4]);
+
+#[test]
+fn map() {
+    let mut m = std::collections::HashMap::new();
+    m.insert(1, 2);
+    m.insert(5, 3);
+    drop(m);
+}
+
+#[test]
+fn aligned_and_realloc() {
+    unsafe {
+        for align in [1, 8, 16, 32, 4096] {
+            let layout = Layout::from_size_align(100, align).unwrap();
+            let ptr = A.alloc_zeroed(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            assert!((0..100).all(|i| *ptr.add(i) == 0));
+            ptr.write_bytes(0xab, 100);
+            let ptr = A.realloc(ptr, layout, 10_000);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            assert!((0..100).all(|i| *ptr.add(i) == 0xab));
+            A.dealloc(ptr, Layout::from_size_align(10_000, align).unwrap());
+        }
+    }
+}
+
+#[test]
+fn cross_thread_frees() {
+    let (tx, rx) = mpsc::channel::<Vec<Vec<u8>>>();
+    let consumer = thread::spawn(move || {
+        for batch in rx {
+            for (i, v) in batch.into_iter().enumerate() {
+                assert!(v.iter().all(|b| *b == i as u8));
+            }
+        }
+    });
+    let producers = (0..8)
+        .map(|_| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let batch = (0..64).map(|i| vec![i as u8; i * 7]).collect();
+                    tx.send(batch).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(tx);
+    for t in producers {
+        t.join().unwrap();
+    }
+    consumer.join().unwrap();
+
+    let stats = A.stats();
+    assert_eq!(stats.used + stats.free, stats.footprint);
+    A.trim(0);
+}
+
+#[test]
+fn contended_frees() {
+    // Everything is allocated on one thread, so it mostly comes from one
+    // arena, and then freed from many threads at once so most frees find
+    // that arena locked and go through its remote list.
+    let batches = (0..16)
+        .map(|_| (0..500).map(|i| vec![i as u8; 48]).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let threads = batches
+        .into_iter()
+        .map(|batch| {
+            thread::spawn(move || {
+                for (i, v) in batch.into_iter().enumerate() {
+                    assert!(v.iter().all(|b| *b == i as u8));
+                    drop(v);
+                    let _ = vec![0u8; 48];
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+    A.trim(0);
+}
@@ -0,0 +1,47 @@
+#![cfg(all(feature = "global", any(target_os = "linux", target_os = "macos")))]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+#[global_allocator]
+static A: dlmalloc::GlobalDlmalloc = dlmalloc::GlobalDlmalloc;
+
+#[test]
+fn fork_while_allocating() {
+    let done = Arc::new(AtomicBool::new(false));
+    let hammer = {
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut v = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                v.push(vec![0u8; 100]);
+                if v.len() > 1000 {
+                    v.clear();
+                }
+            }
+        })
+    };
+
+    for _ in 0..100 {
+        unsafe {
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                // Kill the child if it deadlocks instead of hanging the test.
+                libc::alarm(10);
+                let v = (0..100).map(|i| vec![i as u8; i]).collect::<Vec<_>>();
+                let ok = v.iter().enumerate().all(|(i, v)| v.len() == i);
+                drop(v);
+                libc::_exit(if ok { 0 } else { 1 });
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFEXITED(status), "child didn't exit: {status:#x}");
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+        }
+    }
+
+    done.store(true, Ordering::Relaxed);
+    hammer.join().unwrap();
+}
@@ -30,3 +30,48 @@ fn strings() {
 fn threads() {
     assert!(thread::spawn(|| panic!()).join().is_err());
 }
+
+#[test]
+#[cfg(feature = "global")]
+fn configure_and_trim() {
+    let before = A.stats();
+    let v = vec![0u8; 1 << 20];
+    let during = A.stats();
+    assert!(during.used >= before.used + v.len());
+    drop(v);
+    A.trim(0);
+    assert!(A.configure(|heap| heap.set_granularity(4096)));
+    let after = A.stats();
+    assert!(after.footprint <= after.max_footprint);
+}
+
+#[test]
+#[cfg(all(feature = "global", any(target_os = "linux", target_os = "macos")))]
+fn try_alloc_while_locked() {
+    use std::alloc::Layout;
+
+    let layout = Layout::new::<[u64; 4]>();
+    let ptr = A.configure(|_| unsafe { A.try_alloc(layout) });
+    assert!(ptr.is_null());
+    unsafe {
+        let ptr = A.try_alloc(layout);
+        assert!(!ptr.is_null());
+        std::alloc::GlobalAlloc::dealloc(&A, ptr, layout);
+    }
+}
+
+#[test]
+#[cfg(feature = "global")]
+fn unannotated_instance() {
+    use std::alloc::{GlobalAlloc, Layout};
+
+    // `GlobalDlmalloc` on its own is still a value of a concrete type.
+    let g = dlmalloc::GlobalDlmalloc;
+    let layout = Layout::new::<u64>();
+    unsafe {
+        let ptr = g.alloc(layout);
+        assert!(!ptr.is_null());
+        g.dealloc(ptr, layout);
+    }
+    assert!(g.stats().footprint > 0);
+}
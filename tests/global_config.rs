@@ -0,0 +1,20 @@
+#![cfg(feature = "global")]
+
+use dlmalloc::GlobalDlmallocWith;
+
+// A global allocator configured in its declaration.
+#[global_allocator]
+static A: GlobalDlmallocWith<{ 1 << 21 }, 0> = GlobalDlmallocWith::new();
+
+#[test]
+fn granularity() {
+    let v = vec![1u8; 100_000];
+    // Nothing can be allocated while the heap is borrowed.
+    let (count, aligned) = A.configure(|heap| {
+        let count = heap.segments().count();
+        (count, heap.segments().all(|s| s.size % (1 << 21) == 0))
+    });
+    assert!(count > 0);
+    assert!(aligned);
+    drop(v);
+}
@@ -0,0 +1,66 @@
+#![cfg(feature = "global")]
+
+use dlmalloc::{GlobalDlmallocWith, GlobalLock, RawMutex, SpinLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+// A global allocator guarded by a lock of its own, which counts how often it's
+// taken.
+#[global_allocator]
+static A: GlobalDlmallocWith<{ 64 * 1024 }, 4095, CountingLock> = GlobalDlmallocWith::new();
+
+static TAKEN: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingLock(SpinLock);
+
+unsafe impl RawMutex for CountingLock {
+    const INIT: CountingLock = CountingLock(SpinLock::new());
+
+    fn lock(&self) {
+        TAKEN.fetch_add(1, Ordering::Relaxed);
+        self.0.lock()
+    }
+
+    fn try_lock(&self) -> bool {
+        self.0.try_lock()
+    }
+
+    unsafe fn unlock(&self) {
+        self.0.unlock()
+    }
+}
+
+unsafe impl GlobalLock for CountingLock {
+    fn instance() -> &'static CountingLock {
+        static LOCK: CountingLock = CountingLock::INIT;
+        &LOCK
+    }
+}
+
+#[test]
+fn uses_the_lock() {
+    let before = TAKEN.load(Ordering::Relaxed);
+    let v = vec![1u8; 1 << 20];
+    drop(v);
+    A.trim(0);
+    assert!(TAKEN.load(Ordering::Relaxed) > before);
+}
+
+#[test]
+#[cfg(not(target_family = "wasm"))]
+fn threads() {
+    let threads = (0..4)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut v = Vec::new();
+                for j in 0..1000 {
+                    v.push(vec![i as u8; j % 100]);
+                }
+                assert!(v.iter().all(|x| x.iter().all(|&b| b == i as u8)));
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+use dlmalloc::{Dlmalloc, LockedDlmalloc, SpinLock, System};
+use std::alloc::{GlobalAlloc, Layout};
+use std::collections::HashMap;
+use std::thread;
+
+#[global_allocator]
+static A: LockedDlmalloc<System, SpinLock> = LockedDlmalloc::new(Dlmalloc::new());
+
+#[test]
+fn map() {
+    let mut m = HashMap::new();
+    m.insert(1, 2);
+    m.insert(5, 3);
+    drop(m);
+}
+
+#[test]
+#[cfg(not(target_family = "wasm"))]
+fn threads() {
+    let threads = (0..4)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut v = Vec::new();
+                for j in 0..1000 {
+                    v.push(vec![i as u8; j % 100]);
+                }
+                v.len()
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        assert_eq!(t.join().unwrap(), 1000);
+    }
+}
+
+// A `LockedDlmalloc` doesn't need to be the global allocator, and the heap it
+// wraps can be configured in a `const` block.
+#[test]
+fn standalone_static() {
+    static B: LockedDlmalloc<System, SpinLock> = LockedDlmalloc::new(
+        const {
+            let mut a = Dlmalloc::new();
+            assert!(a.set_granularity(4096));
+            a
+        },
+    );
+    unsafe {
+        let layout = Layout::from_size_align(64, 32).unwrap();
+        let ptr = B.alloc_zeroed(layout);
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize & 31, 0);
+        assert_eq!(*ptr.add(63), 0);
+        let ptr = B.realloc(ptr, layout, 128);
+        assert!(!ptr.is_null());
+        B.dealloc(ptr, Layout::from_size_align(128, 32).unwrap());
+    }
+}
+
+#[test]
+fn stats_and_trim() {
+    let v = vec![1u8; 4 << 20];
+    assert!(A.stats().used >= v.len());
+    drop(v);
+    A.trim(0);
+    A.configure(|heap| heap.set_max_release_check_rate(16));
+}
+
+/// Hammers a standalone heap guarded by `L` from several threads, `iters`
+/// allocations each, checking that the lock keeps allocations from different
+/// threads apart.
+fn contend<L: dlmalloc::RawMutex + Sync + 'static>(
+    heap: &'static LockedDlmalloc<System, L>,
+    iters: usize,
+) {
+    let threads = (0..4u8)
+        .map(|t| {
+            thread::spawn(move || unsafe {
+                let layout = Layout::from_size_align(64, 8).unwrap();
+                let mut ptrs = Vec::new();
+                for i in 0..iters {
+                    let ptr = heap.alloc(layout);
+                    assert!(!ptr.is_null());
+                    ptr.write_bytes(t, 64);
+                    ptrs.push(ptr);
+                    if i % 2 == 0 {
+                        let ptr = ptrs.swap_remove(i % ptrs.len());
+                        assert!((0..64).all(|j| *ptr.add(j) == t));
+                        heap.dealloc(ptr, layout);
+                    }
+                }
+                for ptr in ptrs {
+                    assert!((0..64).all(|j| *ptr.add(j) == t));
+                    heap.dealloc(ptr, layout);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+#[test]
+#[cfg(not(target_family = "wasm"))]
+fn spin_lock() {
+    static B: LockedDlmalloc<System, SpinLock> = LockedDlmalloc::new(Dlmalloc::new());
+    contend(&B, 2000);
+}
+
+#[test]
+#[cfg(not(target_family = "wasm"))]
+fn ticket_lock() {
+    static B: LockedDlmalloc<System, dlmalloc::TicketLock> = LockedDlmalloc::new(Dlmalloc::new());
+    // Fewer rounds, as waiters don't yield, which is slow with fewer cores
+    // than threads.
+    contend(&B, 200);
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn pthread_mutex() {
+    static B: LockedDlmalloc<System, dlmalloc::PthreadMutex> = LockedDlmalloc::new(Dlmalloc::new());
+    contend(&B, 2000);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn futex_lock() {
+    static B: LockedDlmalloc<System, dlmalloc::FutexLock> = LockedDlmalloc::new(Dlmalloc::new());
+    contend(&B, 2000);
+}
+
+#[test]
+#[cfg(windows)]
+fn srw_lock() {
+    static B: LockedDlmalloc<System, dlmalloc::SrwLock> = LockedDlmalloc::new(Dlmalloc::new());
+    contend(&B, 2000);
+}
+
+#[test]
+fn static_region() {
+    use dlmalloc::NoFallback;
+    use std::mem::MaybeUninit;
+    use std::ptr;
+
+    static mut HEAP: [MaybeUninit<u8>; 16 * 1024] = [MaybeUninit::uninit(); 16 * 1024];
+    static B: LockedDlmalloc<NoFallback, SpinLock> = LockedDlmalloc::new(
+        Dlmalloc::with_region_and_allocator(unsafe { &mut *ptr::addr_of_mut!(HEAP) }, NoFallback),
+    );
+
+    unsafe {
+        let layout = Layout::from_size_align(1024, 64).unwrap();
+        let ptr = B.alloc(layout);
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % 64, 0);
+        assert!(B
+            .alloc(Layout::from_size_align(32 * 1024, 8).unwrap())
+            .is_null());
+        B.dealloc(ptr, layout);
+    }
+}
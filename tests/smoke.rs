@@ -178,6 +178,71 @@ fn stress() {
     }
 }
 
+// Runs the same workload with a system allocator reporting pages larger than
+// the heap's granularity, as on kernels with 16K or 64K pages.
+#[test]
+#[cfg(unix)]
+fn stress_large_pages() {
+    use dlmalloc::{Allocator, System};
+
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut buf = vec![0; 4096];
+    let iters = if cfg!(miri) { 5 } else { 200 };
+    for page_size in [16 * 1024, 256 * 1024] {
+        for _ in 0..iters {
+            rng.fill_bytes(&mut buf);
+            let mut u = Unstructured::new(&buf);
+            let system = System::new().with_page_size(page_size);
+            assert_eq!(system.page_size(), page_size);
+            let _ = fuzz::run_with(&mut u, Dlmalloc::new_with_allocator(system));
+        }
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn page_size_at_least_kernel_page() {
+    use dlmalloc::{Allocator, System};
+
+    let kernel = System::new().page_size();
+    assert_eq!(System::new().with_page_size(kernel / 4).page_size(), kernel);
+    assert_eq!(
+        System::new().with_page_size(kernel * 4).page_size(),
+        kernel * 4
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn large_pages_keep_segments_aligned() {
+    use dlmalloc::System;
+
+    const PAGE: usize = 256 * 1024;
+
+    let mut a = Dlmalloc::new_with_allocator(System::new().with_page_size(PAGE));
+    a.set_reserve_size(64 << 20);
+    unsafe {
+        let keep = a.malloc(100, 8);
+        let ptrs = (0..10)
+            .map(|i| a.malloc(100_000 * i + 1, 8))
+            .collect::<Vec<_>>();
+        for segment in a.segments() {
+            assert_eq!(segment.size % PAGE, 0);
+        }
+        for (i, ptr) in ptrs.into_iter().enumerate() {
+            a.free(ptr, 100_000 * i + 1, 8);
+        }
+        a.trim(0);
+        assert!(a.stats().footprint <= 2 * PAGE);
+        for segment in a.segments() {
+            assert_eq!(segment.size % PAGE, 0);
+        }
+        assert_eq!(a.stats().footprint % PAGE, 0);
+        a.free(keep, 100, 8);
+        a.destroy();
+    }
+}
+
 // Exercises the public configuration API (`set_max_release_check_rate`,
 // `set_granularity`) end-to-end through the `Dlmalloc<System>` wrapper,
 // configured via a `const` block to also verify the const-fn chain.
@@ -246,3 +311,1002 @@ fn sub_page_granularity_alloc_free() {
         }
     }
 }
+
+#[test]
+fn stats() {
+    let mut a = Dlmalloc::new();
+    assert_eq!(a.stats(), Default::default());
+    unsafe {
+        let ptr = a.malloc(1000, 8);
+        assert!(!ptr.is_null());
+        let stats = a.stats();
+        assert!(stats.footprint > 0);
+        assert_eq!(stats.footprint, stats.max_footprint);
+        assert_eq!(stats.used + stats.free, stats.footprint);
+        assert!(stats.used >= 1000);
+        assert_eq!(stats.free_chunks, 1);
+        assert!(stats.releasable > 0);
+
+        a.free(ptr, 1000, 8);
+        let freed = a.stats();
+        assert!(freed.used < 1000);
+        assert_eq!(freed.footprint, stats.footprint);
+    }
+}
+
+// Memory can't be given back on wasm, so nothing is ever unmapped.
+#[test]
+#[cfg(not(target_family = "wasm"))]
+fn owned_releases_memory_on_drop() {
+    use dlmalloc::{Allocator, OwnedDlmalloc, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static MAPPED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counting(System);
+
+    unsafe impl Allocator for Counting {
+        fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
+            let (ptr, size, flags) = self.0.alloc(size);
+            MAPPED.fetch_add(size, Ordering::SeqCst);
+            (ptr, size, flags)
+        }
+        fn remap(&self, ptr: *mut u8, oldsize: usize, newsize: usize, can_move: bool) -> *mut u8 {
+            let ptr = self.0.remap(ptr, oldsize, newsize, can_move);
+            if !ptr.is_null() {
+                MAPPED.fetch_add(newsize - oldsize, Ordering::SeqCst);
+            }
+            ptr
+        }
+        fn free_part(&self, ptr: *mut u8, oldsize: usize, newsize: usize) -> bool {
+            let freed = self.0.free_part(ptr, oldsize, newsize);
+            if freed {
+                MAPPED.fetch_sub(oldsize - newsize, Ordering::SeqCst);
+            }
+            freed
+        }
+        fn free(&self, ptr: *mut u8, size: usize) -> bool {
+            let freed = self.0.free(ptr, size);
+            if freed {
+                MAPPED.fetch_sub(size, Ordering::SeqCst);
+            }
+            freed
+        }
+        fn can_release_part(&self, flags: u32) -> bool {
+            self.0.can_release_part(flags)
+        }
+        fn allocates_zeros(&self) -> bool {
+            self.0.allocates_zeros()
+        }
+        fn page_size(&self) -> usize {
+            self.0.page_size()
+        }
+    }
+
+    let result = std::panic::catch_unwind(|| {
+        let mut a =
+            unsafe { OwnedDlmalloc::new(Dlmalloc::new_with_allocator(Counting(System::new()))) };
+        unsafe {
+            for i in 0..100 {
+                assert!(!a.malloc(i * 1000, 8).is_null());
+            }
+        }
+        assert!(MAPPED.load(Ordering::SeqCst) > 0);
+        panic!("request failed");
+    });
+    assert!(result.is_err());
+    assert_eq!(MAPPED.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn reset_reuses_memory() {
+    let mut a = Dlmalloc::new();
+    unsafe {
+        a.reset();
+        let mut max_footprint = None;
+        for _ in 0..4 {
+            // Replay the same pattern each round, which must fit in the
+            // memory the first round mapped.
+            let mut rng = SmallRng::seed_from_u64(0);
+            for _ in 0..500 {
+                let size = (rng.next_u32() % (64 * 1024)) as usize;
+                let ptr = a.malloc(size, 8);
+                assert!(!ptr.is_null());
+                ptr.write_bytes(0xcd, size);
+                if rng.next_u32() % 3 == 0 {
+                    a.free(ptr, size, 8);
+                }
+            }
+            a.reset();
+            let stats = a.stats();
+            // Only segment bookkeeping stays in use.
+            assert!(stats.used < 1024);
+            assert_eq!(stats.used + stats.free, stats.footprint);
+            assert_eq!(
+                *max_footprint.get_or_insert(stats.max_footprint),
+                stats.max_footprint
+            );
+        }
+    }
+}
+
+#[test]
+fn checkpoint_rollback() {
+    let mut a = Dlmalloc::new();
+    unsafe {
+        let keep = a.malloc(100, 8);
+        keep.write_bytes(1, 100);
+        let used = a.stats().used;
+
+        let outer = a.checkpoint();
+        let mut ptrs = Vec::new();
+        for i in 0..1000 {
+            let align = if i % 7 == 0 { 64 } else { 8 };
+            let size = 16 + i % 300;
+            let ptr = a.malloc(size, align);
+            // Filling allocations mustn't disturb their tracking.
+            ptr.write_bytes(0xab, size);
+            ptrs.push((ptr, size, align));
+        }
+        // Free some of them, and grow others, before rolling back.
+        for (i, (ptr, size, align)) in ptrs.iter_mut().enumerate() {
+            match i % 3 {
+                0 => a.free(*ptr, *size, *align),
+                1 => {
+                    *ptr = a.realloc(*ptr, *size, *align, *size * 5);
+                    assert!(!ptr.is_null());
+                    ptr.write_bytes(0xcd, *size * 5);
+                }
+                _ => {}
+            }
+        }
+        // Allocations in a committed inner checkpoint belong to the outer one.
+        let inner = a.checkpoint();
+        assert!(!a.malloc(5000, 8).is_null());
+        a.commit(inner);
+
+        a.rollback(outer);
+        assert_eq!(a.stats().used, used);
+        assert!((0..100).all(|i| *keep.add(i) == 1));
+
+        // Rolling back an inner checkpoint keeps the outer one's allocations.
+        let outer = a.checkpoint();
+        let x = a.malloc(200, 8);
+        x.write_bytes(2, 200);
+        let used_x = a.stats().used;
+        let inner = a.checkpoint();
+        assert!(!a.calloc(300, 8).is_null());
+        a.rollback(inner);
+        assert!((0..200).all(|i| *x.add(i) == 2));
+        a.commit(outer);
+        assert!(a.stats().used <= used_x);
+
+        a.free(x, 200, 8);
+        assert_eq!(a.stats().used, used);
+        a.free(keep, 100, 8);
+    }
+}
+
+#[test]
+fn with_region() {
+    use dlmalloc::NoFallback;
+    use std::mem::MaybeUninit;
+
+    // Leaked rather than `static mut` so each test run gets fresh memory.
+    let region = Box::leak(Box::new([MaybeUninit::<u8>::uninit(); 64 * 1024]));
+    let range = region.as_ptr_range();
+    let range = range.start as usize..range.end as usize;
+    let mut a = Dlmalloc::with_region_and_allocator(region, NoFallback);
+    unsafe {
+        let mut ptrs = Vec::new();
+        loop {
+            let ptr = a.malloc(1000, 8);
+            if ptr.is_null() {
+                break;
+            }
+            assert!(range.contains(&(ptr as usize)));
+            ptr.write_bytes(0xab, 1000);
+            ptrs.push(ptr);
+        }
+        assert!(ptrs.len() >= 60);
+        let stats = a.stats();
+        assert_eq!(stats.footprint, 64 * 1024);
+        for ptr in ptrs {
+            a.free(ptr, 1000, 8);
+        }
+        // Nothing is ever given back to `NoFallback`.
+        assert!(!a.trim(0));
+        assert_eq!(a.destroy(), 0);
+    }
+
+    // Once the region is full the system allocator takes over.
+    let region = Box::leak(Box::new([MaybeUninit::<u8>::uninit(); 4096]));
+    let mut a = Dlmalloc::with_region(region);
+    unsafe {
+        let small = a.malloc(100, 8);
+        assert!(!small.is_null());
+        let big = a.malloc(100_000, 8);
+        assert!(!big.is_null());
+        big.write_bytes(1, 100_000);
+        a.free(big, 100_000, 8);
+        a.free(small, 100, 8);
+        assert!(a.destroy() > 0);
+    }
+}
+
+#[test]
+fn add_region() {
+    use dlmalloc::NoFallback;
+    use std::mem::MaybeUninit;
+
+    fn region(len: usize) -> (*mut u8, std::ops::Range<usize>) {
+        let region = Box::leak(vec![MaybeUninit::<u8>::uninit(); len].into_boxed_slice());
+        let base = region.as_mut_ptr().cast::<u8>();
+        (base, base as usize..base as usize + len)
+    }
+
+    let mut a = Dlmalloc::new_with_allocator(NoFallback);
+    unsafe {
+        assert!(a.malloc(16, 8).is_null());
+        let (base, _) = region(8);
+        assert!(!a.add_region(base, 8));
+
+        let (base, first) = region(16 * 1024);
+        assert!(a.add_region(base, 16 * 1024));
+        let mut ptrs = Vec::new();
+        loop {
+            let ptr = a.malloc(1000, 8);
+            if ptr.is_null() {
+                break;
+            }
+            assert!(first.contains(&(ptr as usize)));
+            ptrs.push(ptr);
+        }
+
+        // A second, unrelated region lets the heap keep going.
+        let (base, second) = region(16 * 1024);
+        assert!(a.add_region(base, 16 * 1024));
+        let ptr = a.malloc(1000, 8);
+        assert!(second.contains(&(ptr as usize)));
+        ptr.write_bytes(0xab, 1000);
+        ptrs.push(ptr);
+        assert_eq!(a.stats().footprint, 32 * 1024);
+
+        for ptr in ptrs {
+            a.free(ptr, 1000, 8);
+        }
+        let ptr = a.malloc(8000, 8);
+        assert!(!ptr.is_null());
+        a.free(ptr, 8000, 8);
+        assert_eq!(a.destroy(), 0);
+    }
+}
+
+// `System` can't free memory on wasm, so `destroy` frees nothing there.
+#[test]
+#[cfg(not(target_family = "wasm"))]
+fn segments() {
+    use std::mem::MaybeUninit;
+
+    let mut a = Dlmalloc::new();
+    assert_eq!(a.segments().count(), 0);
+    unsafe {
+        let small = a.malloc(100, 8);
+        let big = a.malloc(1 << 20, 8);
+        let region = Box::leak(vec![MaybeUninit::<u8>::uninit(); 4096].into_boxed_slice());
+        let base = region.as_mut_ptr().cast::<u8>();
+        assert!(a.add_region(base, 4096));
+
+        let segments = a.segments().collect::<Vec<_>>();
+        for ptr in [small, big] {
+            let owners = segments
+                .iter()
+                .filter(|s| s.base <= ptr && ptr < s.base.add(s.size))
+                .count();
+            assert_eq!(owners, 1);
+        }
+        let extern_ = segments.iter().find(|s| s.base == base).unwrap();
+        assert!(extern_.is_extern);
+        assert!(!extern_.releasable);
+        assert_eq!(extern_.size, 4096);
+
+        let owned = segments
+            .iter()
+            .filter(|s| !s.is_extern)
+            .map(|s| s.size)
+            .sum::<usize>();
+        assert!(segments.iter().any(|s| !s.is_extern && s.releasable));
+        assert_eq!(owned + 4096, a.stats().footprint);
+        assert_eq!(a.destroy(), owned);
+    }
+}
+
+// Reservations need memory to be decommitted and freed, which wasm can't do.
+#[test]
+#[cfg(not(target_family = "wasm"))]
+fn reserve_keeps_one_segment() {
+    let mut a = Dlmalloc::new();
+    a.set_reserve_size(64 << 20);
+    unsafe {
+        let keep = a.malloc(100, 8);
+        let ptrs = (0..40)
+            .map(|_| {
+                let ptr = a.malloc(1 << 20, 8);
+                assert!(!ptr.is_null());
+                ptr.write_bytes(0xcd, 1 << 20);
+                ptr
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(a.segments().count(), 1);
+        let footprint = a.stats().footprint;
+        assert!(footprint >= 40 << 20);
+
+        for ptr in ptrs {
+            a.free(ptr, 1 << 20, 8);
+        }
+        a.trim(0);
+        assert!(a.stats().footprint < footprint);
+
+        // Decommitted memory can be committed again, and is zeroed.
+        let ptr = a.calloc(32 << 20, 8);
+        assert!(!ptr.is_null());
+        assert!(std::slice::from_raw_parts(ptr, 32 << 20)
+            .iter()
+            .all(|b| *b == 0));
+        assert_eq!(a.segments().count(), 1);
+        a.free(ptr, 32 << 20, 8);
+
+        // Outgrowing the reservation starts a new one, while the old one
+        // stays in use.
+        let big = a.malloc(100 << 20, 8);
+        assert!(!big.is_null());
+        big.write_bytes(1, 100 << 20);
+        for ptr in [keep, big] {
+            assert!(a
+                .segments()
+                .any(|s| s.base <= ptr && ptr < s.base.add(s.size)));
+        }
+        a.free(big, 100 << 20, 8);
+        a.free(keep, 100, 8);
+        assert!(a.destroy() > 0);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn short_reservation_is_freed() {
+    use dlmalloc::{Allocator, System};
+    use std::cell::Cell;
+
+    // Reserves less than asked for, which the heap can't use.
+    struct Short {
+        system: System,
+        freed: Cell<usize>,
+    }
+
+    unsafe impl Allocator for Short {
+        fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
+            self.system.alloc(size)
+        }
+        fn remap(&self, ptr: *mut u8, oldsize: usize, newsize: usize, can_move: bool) -> *mut u8 {
+            self.system.remap(ptr, oldsize, newsize, can_move)
+        }
+        fn free_part(&self, ptr: *mut u8, oldsize: usize, newsize: usize) -> bool {
+            self.system.free_part(ptr, oldsize, newsize)
+        }
+        fn free(&self, ptr: *mut u8, size: usize) -> bool {
+            self.freed.set(self.freed.get() + 1);
+            self.system.free(ptr, size)
+        }
+        fn can_release_part(&self, flags: u32) -> bool {
+            self.system.can_release_part(flags)
+        }
+        fn allocates_zeros(&self) -> bool {
+            self.system.allocates_zeros()
+        }
+        fn page_size(&self) -> usize {
+            self.system.page_size()
+        }
+        fn reserve(&self, _size: usize) -> (*mut u8, usize, u32) {
+            let (ptr, _, flags) = self.system.reserve(4096);
+            (ptr, 4096, flags)
+        }
+    }
+
+    let mut a = Dlmalloc::new_with_allocator(Short {
+        system: System::new(),
+        freed: Cell::new(0),
+    });
+    a.set_reserve_size(1 << 20);
+    unsafe {
+        let ptr = a.malloc(100, 8);
+        assert!(!ptr.is_null());
+        assert_eq!(a.allocator().freed.get(), 1);
+        a.free(ptr, 100, 8);
+        a.destroy();
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn purge_releases_free_pages() {
+    unsafe fn resident_pages(ptr: *mut u8, len: usize) -> usize {
+        let mut vec = vec![0u8; len / 4096];
+        assert_eq!(libc::mincore(ptr.cast(), len, vec.as_mut_ptr()), 0);
+        vec.iter().filter(|b| **b & 1 != 0).count()
+    }
+
+    let mut a = Dlmalloc::new();
+    unsafe {
+        let big = a.malloc(4 << 20, 8);
+        let guard = a.malloc(100, 8);
+        big.write_bytes(0xab, 4 << 20);
+        let page = big.add(big.align_offset(4096));
+        assert_eq!(resident_pages(page, 2 << 20), 512);
+        a.free(big, 4 << 20, 8);
+
+        assert!(a.purge(usize::MAX) >= (4 << 20) - 8192);
+        assert_eq!(resident_pages(page.add(4096), 2 << 20), 0);
+        // Nothing has been freed since, so there's nothing left to purge.
+        assert_eq!(a.purge(usize::MAX), 0);
+
+        // Purged memory is still usable, and calloc still zeroes it.
+        let ptr = a.calloc(2 << 20, 8);
+        assert!(std::slice::from_raw_parts(ptr, 2 << 20)
+            .iter()
+            .all(|b| *b == 0));
+        ptr.write_bytes(0xcd, 2 << 20);
+        a.free(ptr, 2 << 20, 8);
+        assert!(a.purge(usize::MAX) > 0);
+        let ptr = a.calloc(2 << 20, 8);
+        assert!(std::slice::from_raw_parts(ptr, 2 << 20)
+            .iter()
+            .all(|b| *b == 0));
+        a.free(ptr, 2 << 20, 8);
+
+        a.free(guard, 100, 8);
+        a.destroy();
+    }
+}
+
+// `System` can only purge memory on these.
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn purge_limit_and_decay() {
+    use dlmalloc::Allocator;
+
+    let mut a = Dlmalloc::new();
+    a.set_reserve_size(64 << 20);
+    let page = a.allocator().page_size();
+    unsafe {
+        let ptrs = (0..4)
+            .map(|_| {
+                let ptr = a.malloc(1 << 20, 8);
+                ptr.write_bytes(1, 1 << 20);
+                (ptr, a.malloc(100, 8))
+            })
+            .collect::<Vec<_>>();
+        a.free(ptrs[0].0, 1 << 20, 8);
+        a.free(ptrs[1].0, 1 << 20, 8);
+
+        // A limit is respected exactly, and the rest is picked up later.
+        assert_eq!(a.purge(16 * page), 16 * page);
+        let rest = a.purge(usize::MAX);
+        assert!(rest >= (2 << 20) - 16 * page - 4 * page);
+
+        // Memory must be seen free for the decay time before it's purged.
+        a.set_decay_time(10);
+        a.free(ptrs[2].0, 1 << 20, 8);
+        assert_eq!(a.decay(100), 0);
+        assert_eq!(a.decay(105), 0);
+        a.free(ptrs[3].0, 1 << 20, 8);
+        let first = a.decay(110);
+        assert!(first >= (1 << 20) - 2 * page);
+        assert!(first < 2 << 20);
+        assert!(a.decay(115) == 0);
+        assert!(a.decay(120) >= (1 << 20) - 2 * page);
+
+        for (_, guard) in ptrs {
+            a.free(guard, 100, 8);
+        }
+        a.destroy();
+    }
+}
+
+#[test]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn purge_in_small_steps() {
+    use dlmalloc::Allocator;
+
+    let mut a = Dlmalloc::new();
+    let page = a.allocator().page_size();
+    unsafe {
+        let ptrs = (0..3)
+            .map(|_| {
+                let ptr = a.malloc(1 << 20, 8);
+                ptr.write_bytes(1, 1 << 20);
+                (ptr, a.malloc(100, 8))
+            })
+            .collect::<Vec<_>>();
+        a.free(ptrs[0].0, 1 << 20, 8);
+        a.free(ptrs[2].0, 1 << 20, 8);
+
+        // Less than a page never purges anything.
+        assert_eq!(a.purge(page - 1), 0);
+
+        // A page at a time works through every chunk in the end.
+        let mut total = 0;
+        for _ in 0..2 * (1 << 20) / page + 100 {
+            match a.purge(page) {
+                0 => break,
+                n => {
+                    assert_eq!(n, page);
+                    total += n;
+                }
+            }
+        }
+        assert!(total >= (2 << 20) - 4 * page, "{total}");
+        assert_eq!(a.purge(usize::MAX), 0);
+
+        a.free(ptrs[1].0, 1 << 20, 8);
+        for (_, guard) in ptrs {
+            a.free(guard, 100, 8);
+        }
+        a.destroy();
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn huge_pages() {
+    use dlmalloc::HugePageSystem;
+
+    const PAGE: usize = HugePageSystem::PAGE_SIZE;
+
+    for system in [HugePageSystem::new(), HugePageSystem::hugetlb()] {
+        let mut a = Dlmalloc::new_with_allocator(system);
+        assert!(a.set_granularity(PAGE));
+        unsafe {
+            let keep = a.malloc(100, 8);
+            let ptrs = (0..20)
+                .map(|_| {
+                    let ptr = a.malloc(1 << 20, 8);
+                    assert!(!ptr.is_null());
+                    ptr.write_bytes(0xab, 1 << 20);
+                    ptr
+                })
+                .collect::<Vec<_>>();
+            for segment in a.segments() {
+                assert_eq!(segment.base as usize % PAGE, 0);
+                assert_eq!(segment.size % PAGE, 0);
+            }
+            for ptr in ptrs {
+                a.free(ptr, 1 << 20, 8);
+            }
+            a.trim(0);
+            assert!(a.stats().footprint <= 2 * PAGE);
+            let ptr = a.calloc(3 << 20, 8);
+            assert!(std::slice::from_raw_parts(ptr, 3 << 20)
+                .iter()
+                .all(|b| *b == 0));
+            a.free(ptr, 3 << 20, 8);
+            a.free(keep, 100, 8);
+            a.destroy();
+        }
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn named_mappings() {
+    use dlmalloc::System;
+
+    fn named(base: *mut u8) -> bool {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        maps.lines().any(|line| {
+            let start = line.split('-').next().unwrap();
+            usize::from_str_radix(start, 16).unwrap() == base as usize
+                && line.ends_with("[anon:dlmalloc:smoke-test]")
+        })
+    }
+
+    let system = System::new().with_name(c"dlmalloc:smoke-test");
+    let mut a = Dlmalloc::new_with_allocator(system);
+    let mut b = Dlmalloc::new_with_allocator(System::new().with_name(c"dlmalloc:smoke-test"));
+    b.set_reserve_size(64 << 20);
+    unsafe {
+        let ptr = a.malloc(100, 8);
+        let base = a.segments().next().unwrap().base;
+        let reserved = b.malloc(100, 8);
+        let reserved_base = b.segments().next().unwrap().base;
+
+        // Naming needs a recent enough kernel, otherwise it's skipped.
+        let page = libc::mmap(
+            core::ptr::null_mut(),
+            4096,
+            libc::PROT_READ,
+            libc::MAP_ANON | libc::MAP_PRIVATE,
+            -1,
+            0,
+        );
+        assert_ne!(page, libc::MAP_FAILED);
+        let supported = libc::prctl(
+            libc::PR_SET_VMA,
+            libc::PR_SET_VMA_ANON_NAME as libc::c_ulong,
+            page as libc::c_ulong,
+            4096 as libc::c_ulong,
+            c"probe".as_ptr() as libc::c_ulong,
+        ) == 0;
+        libc::munmap(page, 4096);
+        if supported {
+            assert!(named(base));
+            assert!(named(reserved_base));
+        }
+
+        a.free(ptr, 100, 8);
+        b.free(reserved, 100, 8);
+        a.destroy();
+        b.destroy();
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn file_backed() {
+    use dlmalloc::{Allocator, FileBacked};
+
+    fn file_len(fd: libc::c_int) -> usize {
+        unsafe {
+            let mut stat = core::mem::zeroed::<libc::stat>();
+            assert_eq!(libc::fstat(fd, &mut stat), 0);
+            stat.st_size as usize
+        }
+    }
+
+    let file = FileBacked::memfd(c"dlmalloc-smoke-test", 64 << 20).unwrap();
+    let fd = file.fd();
+    let mut a = Dlmalloc::new_with_allocator(file);
+    unsafe {
+        let ptr = a.malloc(100, 8);
+        ptr.write_bytes(0xab, 100);
+        let big = a.malloc(4 << 20, 8);
+        big.write_bytes(0xcd, 4 << 20);
+        assert!(file_len(fd) >= 4 << 20);
+
+        // Everything the heap hands out can be read back from the file.
+        let mut buf = [0u8; 100];
+        let offset = a.allocator().offset_of(ptr).unwrap();
+        assert_eq!(
+            libc::pread(fd, buf.as_mut_ptr().cast(), 100, offset as libc::off_t),
+            100
+        );
+        assert!(buf.iter().all(|b| *b == 0xab));
+        let offset = a.allocator().offset_of(big.add(1 << 20)).unwrap();
+        assert_eq!(
+            libc::pread(fd, buf.as_mut_ptr().cast(), 100, offset as libc::off_t),
+            100
+        );
+        assert!(buf.iter().all(|b| *b == 0xcd));
+
+        // Writes through the file show up in the heap.
+        buf = [0x11; 100];
+        let offset = a.allocator().offset_of(ptr).unwrap();
+        libc::pwrite(fd, buf.as_ptr().cast(), 100, offset as libc::off_t);
+        assert_eq!(*ptr.add(99), 0x11);
+        assert!(a.allocator().offset_of(core::ptr::null()).is_none());
+
+        // Trimming the heap shrinks the file again.
+        a.free(big, 4 << 20, 8);
+        a.trim(0);
+        assert!(file_len(fd) < 4 << 20);
+        assert_eq!(*ptr, 0x11);
+        a.free(ptr, 100, 8);
+        a.destroy();
+    }
+
+    // Regions freed from the middle of the file are punched out of it.
+    let file = FileBacked::memfd(c"dlmalloc-smoke-test", 64 << 20).unwrap();
+    let blocks = |fd| unsafe {
+        let mut stat = core::mem::zeroed::<libc::stat>();
+        assert_eq!(libc::fstat(fd, &mut stat), 0);
+        stat.st_blocks
+    };
+    unsafe {
+        let regions = (0..3)
+            .map(|_| {
+                let (ptr, size, _) = file.alloc(1 << 20);
+                assert!(!ptr.is_null());
+                ptr.write_bytes(1, size);
+                (ptr, size)
+            })
+            .collect::<Vec<_>>();
+        let (len, before) = (file_len(file.fd()), blocks(file.fd()));
+        assert!(file.free(regions[1].0, regions[1].1));
+        assert_eq!(file_len(file.fd()), len);
+        assert!(blocks(file.fd()) < before);
+        assert_eq!(*regions[2].0, 1);
+    }
+}
+
+#[test]
+fn attach_image() {
+    const LEN: usize = 256 * 1024;
+
+    #[repr(C)]
+    struct Node {
+        next: *mut Node,
+        value: usize,
+    }
+
+    let mut first = vec![0u128; LEN / 16].into_boxed_slice();
+    let mut second = vec![0u128; LEN / 16].into_boxed_slice();
+    let first = first.as_mut_ptr().cast::<u8>();
+    let second = second.as_mut_ptr().cast::<u8>();
+    unsafe {
+        assert!(Dlmalloc::attach(first, LEN).is_none());
+        assert!(Dlmalloc::create_image(first, 16).is_none());
+
+        // Build a list, with some garbage freed along the way so there's
+        // something in the bins.
+        let a = Dlmalloc::create_image(first, LEN).unwrap();
+        let mut head = core::ptr::null_mut::<Node>();
+        let mut garbage = Vec::new();
+        for i in 0..500 {
+            let node = a.malloc(16, 8).cast::<Node>();
+            assert!(!node.is_null());
+            node.write(Node {
+                next: head,
+                value: i,
+            });
+            head = node;
+            let size = i * 7 % 200 + 1;
+            garbage.push((a.malloc(size, 8), size));
+            if i % 3 == 0 {
+                let (ptr, size) = garbage.swap_remove(i * 13 % garbage.len());
+                a.free(ptr, size, 8);
+            }
+        }
+        a.set_root(head.cast());
+        let first_node = head;
+
+        // Reopen it in place, and then as a copy somewhere else.
+        let a = Dlmalloc::attach(first, LEN).unwrap();
+        assert_eq!(a.root(), first_node.cast());
+        std::ptr::copy_nonoverlapping(first, second, LEN);
+        let b = Dlmalloc::attach(second, LEN).unwrap();
+        let delta = second as isize - first as isize;
+        let mut node = b.root().cast::<Node>();
+        assert_eq!(node, first_node.wrapping_byte_offset(delta));
+        for i in (0..500).rev() {
+            assert!((node as usize).wrapping_sub(second as usize) < LEN);
+            assert_eq!((*node).value, i);
+            // Pointers inside allocations are still to the first copy.
+            node = (*node).next;
+            if !node.is_null() {
+                node = node.wrapping_byte_offset(delta);
+            }
+        }
+
+        // The copy keeps working, and is still a valid image.
+        let mut ptrs = Vec::new();
+        for i in 0..500 {
+            let size = i * 11 % 300 + 1;
+            let ptr = b.malloc(size, 8);
+            assert!((ptr as usize).wrapping_sub(second as usize) < LEN);
+            ptrs.push((ptr, size));
+        }
+        for (ptr, size) in ptrs.into_iter().step_by(2) {
+            b.free(ptr, size, 8);
+        }
+        assert!(Dlmalloc::attach(second, LEN).is_some());
+
+        // Damage is noticed.
+        assert!(Dlmalloc::attach(second, LEN - 16).is_none());
+        let head = b.root().cast::<usize>().sub(1);
+        let saved = *head;
+        *head = 0x7ffff0;
+        assert!(Dlmalloc::attach(second, LEN).is_none());
+        *head = saved;
+        assert!(Dlmalloc::attach(second, LEN).is_some());
+
+        // So is a misaligned segment too small to reach its first chunk. The
+        // heap records where it is relative to the heap itself.
+        let segment = b.segments().next().unwrap();
+        let offset = segment.base as usize - (b as *mut Dlmalloc<_> as usize);
+        let words = second.cast::<usize>();
+        let record = (0..256)
+            .find(|&i| *words.add(i) == offset && *words.add(i + 1) == segment.size)
+            .unwrap();
+        *words.add(record) += 1;
+        *words.add(record + 1) = 1;
+        assert!(Dlmalloc::attach(second, LEN).is_none());
+        *words.add(record) -= 1;
+        *words.add(record + 1) = segment.size;
+        assert!(Dlmalloc::attach(second, LEN).is_some());
+        *second = b'x';
+        assert!(Dlmalloc::attach(second, LEN).is_none());
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn shared_image() {
+    const LEN: usize = 1 << 20;
+
+    // Map the same memory twice, as two processes sharing it would.
+    let views = unsafe {
+        let fd = libc::memfd_create(c"dlmalloc-shared-image".as_ptr(), 0);
+        assert!(fd >= 0);
+        assert_eq!(libc::ftruncate(fd, LEN as libc::off_t), 0);
+        let map = || {
+            let ptr = libc::mmap(
+                core::ptr::null_mut(),
+                LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+            assert_ne!(ptr, libc::MAP_FAILED);
+            ptr.cast::<u8>()
+        };
+        let views = [map(), map()];
+        libc::close(fd);
+        views
+    };
+    assert_ne!(views[0], views[1]);
+
+    unsafe {
+        let a = Dlmalloc::create_image(views[0], LEN).unwrap();
+        let b = Dlmalloc::attach(views[1], LEN).unwrap();
+
+        // Take turns allocating through either view, each only ever seeing
+        // addresses in its own.
+        let mut ptrs = Vec::new();
+        for i in 0..2000 {
+            let view = i % 2;
+            let heap = if view == 0 { &mut *a } else { &mut *b };
+            let size = i * 37 % 1000 + 1;
+            let ptr = heap.malloc(size, 8);
+            assert!((ptr as usize).wrapping_sub(views[view] as usize) < LEN);
+            ptr.write_bytes(i as u8, size);
+            ptrs.push((ptr as usize - views[view] as usize, size, i as u8));
+            if i % 3 == 0 {
+                // Free something allocated through the other view.
+                let (offset, size, byte) = ptrs.swap_remove(i * 7 % ptrs.len());
+                let ptr = views[1 - view].add(offset);
+                assert!(core::slice::from_raw_parts(ptr, size)
+                    .iter()
+                    .all(|b| *b == byte));
+                let heap = if view == 0 { &mut *b } else { &mut *a };
+                heap.free(ptr, size, 8);
+            }
+        }
+        a.set_root(views[0].add(ptrs[0].0));
+        assert_eq!(b.root(), views[1].add(ptrs[0].0));
+
+        // Every allocation is still intact, and both views agree the heap is.
+        for (offset, size, byte) in ptrs {
+            let ptr = views[1].add(offset);
+            assert!(core::slice::from_raw_parts(ptr, size)
+                .iter()
+                .all(|b| *b == byte));
+            b.free(ptr, size, 8);
+        }
+        assert!(Dlmalloc::attach(views[0], LEN).is_some());
+        assert!(Dlmalloc::attach(views[1], LEN).is_some());
+
+        for view in views {
+            libc::munmap(view.cast(), LEN);
+        }
+    }
+}
+
+#[test]
+fn fallback() {
+    use dlmalloc::{Allocator, Fallback, System};
+    use std::cell::Cell;
+
+    // Hands out one fixed region, remembering what it was asked to free.
+    struct Region {
+        base: usize,
+        len: usize,
+        taken: Cell<bool>,
+        freed: Cell<usize>,
+    }
+
+    impl Region {
+        fn new(len: usize) -> Region {
+            let region = Box::leak(vec![0u128; len / 16].into_boxed_slice());
+            Region {
+                base: region.as_mut_ptr() as usize,
+                len,
+                taken: Cell::new(false),
+                freed: Cell::new(0),
+            }
+        }
+
+        fn holds(&self, ptr: *mut u8) -> bool {
+            (ptr as usize).wrapping_sub(self.base) < self.len
+        }
+    }
+
+    unsafe impl Allocator for Region {
+        fn alloc(&self, size: usize) -> (*mut u8, usize, u32) {
+            if self.taken.get() || size > self.len {
+                return (core::ptr::null_mut(), 0, 0);
+            }
+            self.taken.set(true);
+            (self.base as *mut u8, self.len, 0)
+        }
+        fn remap(
+            &self,
+            _ptr: *mut u8,
+            _oldsize: usize,
+            _newsize: usize,
+            _can_move: bool,
+        ) -> *mut u8 {
+            core::ptr::null_mut()
+        }
+        fn free_part(&self, _ptr: *mut u8, _oldsize: usize, _newsize: usize) -> bool {
+            false
+        }
+        fn free(&self, ptr: *mut u8, size: usize) -> bool {
+            assert_eq!((ptr as usize, size), (self.base, self.len));
+            self.freed.set(self.freed.get() + 1);
+            self.taken.set(false);
+            true
+        }
+        fn can_release_part(&self, _flags: u32) -> bool {
+            true
+        }
+        fn allocates_zeros(&self) -> bool {
+            false
+        }
+        fn page_size(&self) -> usize {
+            4096
+        }
+    }
+
+    let chain = Fallback::new(
+        Fallback::new(Region::new(64 * 1024), Region::new(64 * 1024)),
+        System::new(),
+    );
+    let mut a = Dlmalloc::new_with_allocator(chain);
+    unsafe {
+        // Small allocations fill the first region, then the second one.
+        let first = a.malloc(1000, 8);
+        assert!(a.allocator().primary().primary().holds(first));
+        let mut ptrs = Vec::new();
+        let mut in_second = None;
+        for _ in 0..100 {
+            let ptr = a.malloc(1000, 8);
+            ptrs.push(ptr);
+            if a.allocator().primary().secondary().holds(ptr) {
+                in_second = Some(ptr);
+                break;
+            }
+        }
+        assert!(in_second.is_some());
+
+        // Big ones spill over into the system allocator.
+        let big = a.malloc(1 << 20, 8);
+        assert!(!a.allocator().primary().primary().holds(big));
+        assert!(!a.allocator().primary().secondary().holds(big));
+        big.write_bytes(1, 1 << 20);
+
+        // Freeing and trimming gives each segment back to where it came from.
+        a.free(big, 1 << 20, 8);
+        for ptr in ptrs {
+            a.free(ptr, 1000, 8);
+        }
+        a.trim(0);
+        assert_eq!(a.allocator().primary().secondary().freed.get(), 1);
+        // What's left is the first region and the part of the system's
+        // segment holding top.
+        let footprint = a.stats().footprint;
+        assert!(footprint <= 2 * 64 * 1024);
+        a.free(first, 1000, 8);
+        assert_eq!(a.destroy(), footprint);
+    }
+}
@@ -0,0 +1,52 @@
+#![cfg(feature = "tcache")]
+
+use std::thread;
+
+#[global_allocator]
+static A: dlmalloc::GlobalDlmalloc = dlmalloc::GlobalDlmalloc;
+
+#[test]
+fn reuse_small() {
+    let a = Box::new([1u8; 24]);
+    drop(a);
+    let b = Box::new([0u8; 24]);
+    assert!(b.iter().all(|b| *b == 0));
+    let c = Box::new([2u64; 3]);
+    assert_eq!(*c, [2, 2, 2]);
+}
+
+#[test]
+fn flush_past_threshold() {
+    let boxes = (0..1000).map(Box::new).collect::<Vec<_>>();
+    drop(boxes);
+    let boxes = (0..1000).map(Box::new).collect::<Vec<_>>();
+    for (i, b) in boxes.iter().enumerate() {
+        assert_eq!(**b, i);
+    }
+}
+
+#[test]
+fn threads() {
+    let threads = (0..8)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut v = Vec::new();
+                for j in 0..10_000usize {
+                    v.push(vec![i as u8; j % 700]);
+                    if j % 3 == 0 {
+                        v.swap_remove(j % v.len());
+                    }
+                }
+                v
+            })
+        })
+        .collect::<Vec<_>>();
+    // Free memory allocated by other threads, which lands in this thread's
+    // cache.
+    for (i, t) in threads.into_iter().enumerate() {
+        for v in t.join().unwrap() {
+            assert!(v.iter().all(|b| *b == i as u8));
+        }
+    }
+    A.trim(0);
+}